@@ -2,26 +2,41 @@ use anyhow::{Context, Result};
 use pest::Parser;
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use twml::parser::{DocumentParser, LexerState, Rule};
 
 fn main() -> Result<()> {
     let arguments: Vec<String> = env::args().collect();
+    let mut template_directories: Vec<PathBuf> = Vec::new();
+    let mut paths: Vec<String> = Vec::new();
+    let mut iterator = arguments.iter().skip(1);
 
-    if arguments.len() != 3 {
-        println!("Usage: {} <input.twml> <output.html>", arguments[0]);
+    while let Some(argument) = iterator.next() {
+        match argument.as_str() {
+            "--template-dir" => template_directories.extend(iterator.next().map(PathBuf::from)),
+            _ => paths.push(argument.clone()),
+        }
+    }
+
+    if paths.len() != 2 {
+        println!(
+            "Usage: {} [--template-dir <directory>]... <input.twml> <output.html>",
+            arguments[0]
+        );
         exit(22);
     }
 
-    let document =
-        fs::read_to_string(&arguments[1]).context("Failed to read the input document")?;
+    let document = fs::read_to_string(&paths[0]).context("Failed to read the input document")?;
     let pairs = DocumentParser::parse(Rule::document, &document)
         .context("Failed to interpret the provided document")?;
     let mut lex_state = LexerState::default();
+    lex_state.base_directory = Path::new(&paths[0]).parent().unwrap().to_path_buf();
+    lex_state.template_directories = template_directories;
     let html = DocumentParser::generate_html(&mut lex_state, pairs)
         .context("Failed to generate html code")?;
 
-    fs::write(&arguments[2], html).context("Failed to write the output html")?;
+    fs::write(&paths[1], html).context("Failed to write the output html")?;
 
     Ok(())
 }
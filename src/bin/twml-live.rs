@@ -1,11 +1,12 @@
 use anyhow::{Context, Result};
 use notify::Watcher;
-use penguin::Server;
+use penguin::{Controller, Server};
 use pest::Parser;
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use twml::parser::{DocumentParser, LexerState, Rule};
@@ -13,35 +14,79 @@ use twml::parser::{DocumentParser, LexerState, Rule};
 #[tokio::main]
 async fn main() -> Result<()> {
     let arguments: Vec<String> = env::args().collect();
+    let mut template_directories: Vec<PathBuf> = Vec::new();
+    let mut paths: Vec<String> = Vec::new();
+    let mut iterator = arguments.iter().skip(1);
+
+    while let Some(argument) = iterator.next() {
+        match argument.as_str() {
+            "--template-dir" => template_directories.extend(iterator.next().map(PathBuf::from)),
+            _ => paths.push(argument.clone()),
+        }
+    }
 
-    if arguments.len() != 2 {
-        println!("Usage: {} <input.twml>", arguments[0]);
+    if paths.len() != 1 {
+        println!(
+            "Usage: {} [--template-dir <directory>]... <input.twml>",
+            arguments[0]
+        );
         exit(22);
     }
 
-    let document_path =
-        fs::canonicalize(&arguments[1]).context("Received an unexpected input path")?;
-    let document_parent_path = &document_path.parent().unwrap().to_path_buf();
+    let document_path = fs::canonicalize(&paths[0]).context("Received an unexpected input path")?;
+    let document_parent_path = document_path.parent().unwrap().to_path_buf();
 
     let time = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
     let temporary_dir_path = env::temp_dir().join(format!("twml-live-{}", time));
     let temporary_dir_path_drop_copy = temporary_dir_path.clone();
     fs::create_dir(&temporary_dir_path).context("Failed to create a temporary directory")?;
 
-    let index_path = temporary_dir_path.join("index.html");
-    let mut index_file = fs::File::create(&index_path)?;
-
     let (server, controller) = Server::bind(([127, 0, 0, 1], 8080).into())
         .add_mount("/", &temporary_dir_path)?
         .build()?;
 
     println!("Server running on: http://127.0.0.1:8080/");
 
-    let mut last_update = SystemTime::now();
-    let mut watcher = notify::recommended_watcher(
-        move |result: Result<notify::Event, notify::Error>| match result {
-            Ok(event) => {
-                if event.kind.is_modify() {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    watcher.watch(&document_parent_path, notify::RecursiveMode::Recursive)?;
+
+    thread::spawn(move || {
+        // Files referenced by the document (e.g. source code) may live outside of the document
+        // directory and are therefore watched separately
+        let mut watched_dependencies: Vec<PathBuf> = Vec::new();
+        let mut watch_dependencies = |dependencies: Vec<PathBuf>| {
+            for dependency in dependencies {
+                if dependency.starts_with(&document_parent_path)
+                    || watched_dependencies.contains(&dependency)
+                {
+                    continue;
+                }
+
+                match watcher.watch(&dependency, notify::RecursiveMode::NonRecursive) {
+                    Ok(()) => watched_dependencies.push(dependency),
+                    Err(error) => println!("File watch error: {:?}", error),
+                }
+            }
+        };
+
+        watch_dependencies(render(
+            &document_path,
+            &template_directories,
+            &temporary_dir_path,
+            &controller,
+        ));
+
+        let mut last_update = SystemTime::now();
+
+        for result in receiver {
+            match result {
+                Ok(event) => {
+                    if !event.kind.is_modify() {
+                        continue;
+                    }
+
                     // Some editors modify a file multiple times. To mitigate this issue, a simple
                     // limiter is implemented
                     if last_update
@@ -50,7 +95,7 @@ async fn main() -> Result<()> {
                         .as_micros()
                         <= 500
                     {
-                        return;
+                        continue;
                     }
 
                     last_update = SystemTime::now();
@@ -61,43 +106,77 @@ async fn main() -> Result<()> {
                     // happening.
                     thread::sleep(Duration::from_millis(200));
 
-                    let document = fs::read_to_string(&document_path)
-                        .expect("Failed to read the input document");
-                    let pairs_result = DocumentParser::parse(Rule::document, &document);
+                    watch_dependencies(render(
+                        &document_path,
+                        &template_directories,
+                        &temporary_dir_path,
+                        &controller,
+                    ));
+                }
+                Err(error) => println!("File watch error: {:?}", error),
+            }
+        }
+    });
+
+    server.await?;
 
-                    match pairs_result {
-                        Ok(pairs) => {
-                            let mut lex_state = LexerState::default();
-                            let html = DocumentParser::generate_html(&mut lex_state, pairs)
-                                .expect("Failed to generate html code");
+    fs::remove_dir_all(&temporary_dir_path_drop_copy)
+        .context("Failed to clean up temporary directory")?;
 
-                            let _ = index_file.set_len(0);
-                            write!(index_file, "{}", html).unwrap();
+    Ok(())
+}
 
+/// Renders the document into the temporary directory and returns the files it depends on
+fn render(
+    document_path: &Path,
+    template_directories: &[PathBuf],
+    temporary_dir_path: &Path,
+    controller: &Controller,
+) -> Vec<PathBuf> {
+    let document = match fs::read_to_string(document_path) {
+        Ok(document) => document,
+        Err(error) => {
+            controller.show_message(format!("Failed to read the input document: {}", error));
+
+            return Vec::new();
+        }
+    };
+    let pairs_result = DocumentParser::parse(Rule::document, &document);
+
+    match pairs_result {
+        Ok(pairs) => {
+            let mut lex_state = LexerState::default();
+            lex_state.base_directory = document_path.parent().unwrap().to_path_buf();
+            lex_state.template_directories = template_directories.to_vec();
+
+            match DocumentParser::generate_html(&mut lex_state, pairs) {
+                Ok(html) => {
+                    // Linked files may be missing, e.g. while their path is being typed
+                    let result = fs::write(temporary_dir_path.join("index.html"), html)
+                        .context("Failed to write the output html")
+                        .and_then(|()| {
                             DocumentParser::include_linked_files(
                                 &lex_state.declarations,
-                                &temporary_dir_path,
+                                temporary_dir_path,
                             )
-                            .expect("Failed to include linked files");
+                        });
 
-                            controller.reload();
-                        }
+                    match result {
+                        Ok(()) => controller.reload(),
                         Err(error) => {
-                            controller.show_message(error.to_string().replace('\n', "<br>"))
+                            controller.show_message(format!("{:?}", error).replace('\n', "<br>"))
                         }
                     }
                 }
+                Err(error) => controller.show_message(format!("{:?}", error).replace('\n', "<br>")),
             }
-            Err(error) => println!("File watch error: {:?}", error),
-        },
-    )?;
-
-    watcher.watch(document_parent_path, notify::RecursiveMode::Recursive)?;
 
-    server.await?;
-
-    fs::remove_dir_all(&temporary_dir_path_drop_copy)
-        .context("Failed to clean up temporary directory")?;
+            lex_state.dependencies
+        }
+        Err(error) => {
+            controller.show_message(error.to_string().replace('\n', "<br>"));
 
-    Ok(())
+            Vec::new()
+        }
+    }
 }
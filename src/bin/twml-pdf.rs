@@ -6,41 +6,88 @@ use lopdf::{Bookmark, Document, Object};
 use pest::Parser;
 use std::env;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs, fs::File};
+use twml::merge;
 use twml::parser::{Declarations, DocumentParser, LexerState, Rule, TocEntry};
+use twml::placeholder::Scope;
 
 const FACTOR_MM_TO_INCHES: f64 = 25.4;
 
 fn main() -> Result<()> {
     let arguments: Vec<String> = env::args().collect();
+    let mut records_path: Option<String> = None;
+    let mut template_directories: Vec<PathBuf> = Vec::new();
+    let mut paths: Vec<String> = Vec::new();
+    let mut iterator = arguments.iter().skip(1);
+
+    while let Some(argument) = iterator.next() {
+        match argument.as_str() {
+            "--merge" => records_path = iterator.next().cloned(),
+            "--template-dir" => template_directories.extend(iterator.next().map(PathBuf::from)),
+            _ => paths.push(argument.clone()),
+        }
+    }
 
-    if arguments.len() != 3 {
-        println!("Usage: {} <input.twml> <output.pdf>", arguments[0]);
+    if paths.len() != 2 || (arguments.contains(&String::from("--merge")) && records_path.is_none())
+    {
+        println!(
+            "Usage: {} [--merge <records.csv|records.json>] [--template-dir <directory>]... \
+             <input.twml> <output.pdf>",
+            arguments[0]
+        );
         exit(22);
     }
 
-    let document =
-        fs::read_to_string(&arguments[1]).context("Failed to read the input document")?;
+    let document = fs::read_to_string(&paths[0]).context("Failed to read the input document")?;
     let pairs = DocumentParser::parse(Rule::document, &document)
         .context("Failed to interpret the provided document")?;
-    let mut lex_state = LexerState::default();
-    let html = DocumentParser::generate_html(&mut lex_state, pairs)
-        .context("Failed to generate html code")?;
+    let base_directory = Path::new(&paths[0]).parent().unwrap().to_path_buf();
 
     let time = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
     let temporary_dir_path = env::temp_dir().join(format!("twml-live-{}", time));
     fs::create_dir(&temporary_dir_path).context("Failed to create a temporary directory")?;
 
-    let index_path = setup_rendering_env(&lex_state.declarations, &temporary_dir_path, &html)?;
-    export_pdf(
-        &lex_state.declarations,
-        lex_state.toc,
-        &index_path,
-        &arguments[2],
-    )?;
+    let browser = Browser::new(LaunchOptions::default())
+        .context("Failed to initialize a headless_chrome Browser instance")?;
+
+    // Without records to merge, the document is rendered once without any additional variables
+    let (records, output_paths) = match &records_path {
+        Some(records_path) => {
+            let records = merge::read_records(Path::new(records_path))?;
+            let output_paths = merge::output_paths(&paths[1], &records)?;
+
+            (records, output_paths)
+        }
+        None => (vec![Scope::new()], vec![paths[1].clone()]),
+    };
+
+    for (record, output_path) in records.into_iter().zip(output_paths) {
+        let mut lex_state = LexerState::default();
+        lex_state.base_directory = base_directory.clone();
+        lex_state.template_directories = template_directories.clone();
+        lex_state.push_scope(record);
+
+        let html = DocumentParser::generate_html(&mut lex_state, pairs.clone()).context(
+            format!("Failed to generate html code for '{}'", output_path),
+        )?;
+
+        if let Some(output_directory) = Path::new(&output_path).parent() {
+            fs::create_dir_all(output_directory)
+                .context("Failed to create the output directory")?;
+        }
+
+        let index_path = setup_rendering_env(&lex_state.declarations, &temporary_dir_path, &html)?;
+        export_pdf(
+            &browser,
+            &lex_state.declarations,
+            lex_state.toc,
+            &index_path,
+            &output_path,
+        )?;
+    }
 
     fs::remove_dir_all(&temporary_dir_path).context("Failed to clean up temporary directory")?;
 
@@ -62,13 +109,12 @@ fn setup_rendering_env(
 }
 
 fn export_pdf(
+    browser: &Browser,
     declarations: &Declarations,
     toc: Vec<TocEntry>,
     index_path: &str,
     output_pdf_path: &str,
 ) -> Result<()> {
-    let browser = Browser::new(LaunchOptions::default())
-        .context("Failed to initialize a headless_chrome Browser instance")?;
     let tab = browser.new_tab()?;
     let local_pdf = tab
         .navigate_to(&format!("file://{}", index_path))?
@@ -89,6 +135,8 @@ fn export_pdf(
         }))
         .context("Failed to render the pdf")?;
 
+    tab.close(false)?;
+
     let mut output_pdf = File::create(output_pdf_path).context("Failed to create pdf file")?;
 
     if !toc.is_empty() {
@@ -0,0 +1,86 @@
+use crate::placeholder::Value;
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Reads a JSON, TOML or CSV file (depending on its extension) into a value.
+///
+/// Every record of a CSV file becomes a map from the column names of its header to the fields.
+pub fn load(path: &Path) -> Result<Value> {
+    let content = fs::read_to_string(path)
+        .context(format!("Failed to read the data file '{}'", path.display()))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "json" => Ok(from_json(serde_json::from_str(&content).context(
+            format!("Failed to parse the JSON file '{}'", path.display()),
+        )?)),
+        "toml" => Ok(from_toml(toml::from_str(&content).context(format!(
+            "Failed to parse the TOML file '{}'",
+            path.display()
+        ))?)),
+        "csv" => {
+            from_csv(&content).context(format!("Failed to parse the CSV file '{}'", path.display()))
+        }
+        _ => Err(anyhow!(format!(
+            "The data file '{}' is expected to end with .json, .toml or .csv",
+            path.display()
+        ))),
+    }
+}
+
+fn from_json(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(boolean) => Value::Bool(boolean),
+        serde_json::Value::Number(number) => Value::Number(number.as_f64().unwrap_or(f64::NAN)),
+        serde_json::Value::String(string) => Value::String(string),
+        serde_json::Value::Array(items) => Value::List(items.into_iter().map(from_json).collect()),
+        serde_json::Value::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, from_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::Boolean(boolean) => Value::Bool(boolean),
+        toml::Value::Integer(integer) => Value::Number(integer as f64),
+        toml::Value::Float(float) => Value::Number(float),
+        toml::Value::String(string) => Value::String(string),
+        toml::Value::Datetime(date_time) => Value::String(date_time.to_string()),
+        toml::Value::Array(items) => Value::List(items.into_iter().map(from_toml).collect()),
+        toml::Value::Table(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, from_toml(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn from_csv(content: &str) -> Result<Value> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut records: Vec<Value> = Vec::new();
+
+    for record in reader.records() {
+        let record: BTreeMap<String, Value> = headers
+            .iter()
+            .zip(record?.iter())
+            .map(|(header, field)| (header.to_string(), Value::String(field.to_string())))
+            .collect();
+
+        records.push(Value::Map(record));
+    }
+
+    Ok(Value::List(records))
+}
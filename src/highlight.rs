@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Context, Result};
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+pub const DEFAULT_THEME: &str = "InspiredGitHub";
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Returns whether a language (e.g. `rs` or `rust`) can be highlighted
+pub fn is_known_language(language: &str) -> bool {
+    syntax_set().find_syntax_by_token(language).is_some()
+}
+
+/// Highlights code and returns the html of every line (including its line ending) together with
+/// the background color of the theme
+pub fn highlight_lines(
+    code: &str,
+    language: Option<&str>,
+    theme: &str,
+) -> Result<(Vec<String>, Option<String>)> {
+    let syntax_set = syntax_set();
+    let syntax = match language {
+        Some(language) => syntax_set
+            .find_syntax_by_token(language)
+            .context(format!("The code language '{}' is unknown", language))?,
+        None => syntax_set.find_syntax_plain_text(),
+    };
+    let theme = theme_set().themes.get(theme).ok_or(anyhow!(format!(
+        "The code theme '{}' is unknown, expected one of: {}",
+        theme,
+        theme_set()
+            .themes
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .join(", ")
+    )))?;
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in LinesWithEndings::from(code) {
+        let regions = highlighter
+            .highlight_line(line, syntax_set)
+            .context("Failed to highlight code")?;
+
+        lines.push(styled_line_to_highlighted_html(
+            &regions[..],
+            IncludeBackground::No,
+        )?);
+    }
+
+    let background = theme.settings.background.map(|color| {
+        format!(
+            "#{:02x}{:02x}{:02x}{:02x}",
+            color.r, color.g, color.b, color.a
+        )
+    });
+
+    Ok((lines, background))
+}
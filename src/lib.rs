@@ -1,5 +1,9 @@
 #![feature(iter_intersperse)]
+pub mod data;
+pub mod highlight;
+pub mod merge;
 pub mod parser;
+pub mod placeholder;
 
 #[cfg(test)]
 mod tests;
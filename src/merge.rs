@@ -0,0 +1,54 @@
+use crate::data;
+use crate::placeholder::{self, Scope, Value};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::slice;
+
+/// Reads the records of a CSV file or a JSON array of objects, each mapping names to values
+pub fn read_records(path: &Path) -> Result<Vec<Scope>> {
+    let records = match data::load(path)? {
+        Value::List(records) => records,
+        _ => return Err(anyhow!("The records are expected to be a list")),
+    };
+
+    records
+        .into_iter()
+        .map(|record| match record {
+            Value::Map(fields) => Ok(fields.into_iter().collect()),
+            _ => Err(anyhow!("Every record is expected to map names to values")),
+        })
+        .collect()
+}
+
+/// Returns the output path of every record, in which placeholders are replaced by the fields of
+/// the record (e.g. `out/{name}.pdf`).
+///
+/// Separators and `..` within fields are replaced, so that a record cannot name a file outside
+/// of the directory given by the pattern.
+pub fn output_paths(pattern: &str, records: &[Scope]) -> Result<Vec<String>> {
+    let mut output_paths: Vec<String> = Vec::new();
+
+    for (index, record) in records.iter().enumerate() {
+        let fields: Scope = record
+            .iter()
+            .map(|(name, value)| {
+                let field = value.to_string().replace(['/', '\\'], "_");
+
+                (name.clone(), Value::String(field.replace("..", "_")))
+            })
+            .collect();
+        let output_path = placeholder::interpolate(pattern, slice::from_ref(&fields))
+            .context(format!("Failed to name the output of record {}", index + 1))?;
+
+        if output_paths.contains(&output_path) {
+            return Err(anyhow!(format!(
+                "The output path '{}' is shared by multiple records",
+                output_path
+            )));
+        }
+
+        output_paths.push(output_path);
+    }
+
+    Ok(output_paths)
+}
@@ -1,3 +1,6 @@
+use crate::data;
+use crate::highlight;
+use crate::placeholder::{self, Scope, Segment, Value};
 use anyhow::{anyhow, Context, Result};
 use dyn_fmt::AsStrFormatExt;
 use fancy_regex::Regex;
@@ -8,11 +11,28 @@ use pest::iterators::{Pair, Pairs};
 use pest::Parser;
 use pest_derive::Parser;
 use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::mem;
 use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
+use std::sync::OnceLock;
 
 pub type TocEntry = (String, usize);
+/// Local script or stylesheet, along with the path the html references it by
+pub type LinkedFile = (PathBuf, String);
+type Slots = HashMap<String, Vec<HtmlToken>>;
+type LayoutBlocks = HashMap<String, LayoutBlock>;
+
+/// Templates that are handled by the lexer itself instead of a template file
+const BUILTIN_TEMPLATES: [&str; 5] = ["raw", "code", "for", "if", "else"];
+
+/// Elements that must not have any content and are therefore never closed by an end tag.
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
@@ -21,22 +41,95 @@ pub struct DocumentParser;
 #[derive(Debug, Default)]
 pub struct Declarations {
     pub include: Vec<String>,
+    pub links: Vec<LinkedFile>,
     pub js: Vec<String>,
     pub css: Vec<String>,
     pub fonts: Vec<Font>,
     pub page_width_mm: Option<u64>,
     pub page_height_mm: Option<u64>,
+    pub markup: bool,
+    pub markup_classes: MarkupClasses,
+    pub code_theme: Option<String>,
+}
+
+/// Classes of the elements generated by the inline markup (`*bold*`, `_italic_`, `` `code` ``
+/// and `[text](url)`)
+#[derive(Debug, Clone)]
+pub struct MarkupClasses {
+    pub bold: String,
+    pub italic: String,
+    pub code: String,
+    pub link: String,
+}
+
+impl Default for MarkupClasses {
+    fn default() -> Self {
+        MarkupClasses {
+            bold: String::from("font-bold"),
+            italic: String::from("italic"),
+            code: String::from("font-mono"),
+            link: String::from("underline"),
+        }
+    }
+}
+
+/// Attribute accepted by a template, as declared by `@param`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateParameter {
+    pub name: String,
+    pub required: bool,
+    /// Value used if the attribute is omitted by the caller
+    pub default: Option<Value>,
 }
 
 #[derive(Debug, Clone)]
 enum HtmlToken {
-    ElementName { name: String },
-    ElementClasses { classes: Vec<String> },
-    ElementAttributes { attributes: HashMap<String, String> },
-    ElementInlineContent { content: String },
-    ElementChildren { children: Vec<HtmlToken> },
+    ElementName {
+        name: String,
+    },
+    ElementClasses {
+        classes: Vec<String>,
+    },
+    ElementAttributes {
+        attributes: Vec<(String, Option<String>)>,
+    },
+    ElementInlineContent {
+        content: String,
+    },
+    ElementSelfClosing,
+    ElementChildren {
+        children: Vec<HtmlToken>,
+    },
     EmptyBlockLine,
-    BlockLine { content: String },
+    BlockLine {
+        content: String,
+    },
+}
+
+/// Block overriding one of a layout. As it is lexed before the layout, its pages and table of
+/// contents entries are counted from the start of the block until the layout inserts it.
+#[derive(Debug, Clone, Default)]
+struct LayoutBlock {
+    html: Vec<HtmlToken>,
+    pages: usize,
+    toc: Vec<TocEntry>,
+    /// Whether the layout has inserted the block
+    used: bool,
+}
+
+/// Template to be rendered, along with the contents supplied by its caller
+#[derive(Debug, Default)]
+struct Template {
+    path: String,
+    /// Directory that relative paths of the template are resolved against
+    directory: PathBuf,
+    content: String,
+    children: Vec<HtmlToken>,
+    slots: Slots,
+    classes: Vec<String>,
+    attributes: Scope,
+    /// Blocks overridden by the document extending the template, if it is used as a layout
+    blocks: Option<LayoutBlocks>,
 }
 
 #[derive(Debug, Default)]
@@ -45,22 +138,444 @@ pub struct LexerState {
     page_number: usize,
     pub toc: Vec<TocEntry>,
     template_children: Option<Vec<HtmlToken>>,
+    /// Contents of the named slots (`{...name}`) supplied to the current template
+    template_slots: Option<Slots>,
     template_classes: Option<Vec<String>>,
-    template_attributes: HashMap<String, String>,
+    /// Layout extended by the current document (`@extends`)
+    layout: Option<String>,
+    /// Blocks (`\block:name`) overridden by the documents extending the current layout
+    layout_blocks: Option<LayoutBlocks>,
+    /// Sources of the templates defined within the document or any document calling it, along
+    /// with the directory of the defining document
+    definitions: HashMap<String, (String, PathBuf)>,
+    /// Variables visible to the current document, with the innermost scope last
+    scopes: Vec<Scope>,
+    /// Index of the scope that receives the variables set by the current document
+    declaration_scope: usize,
+    raw: bool,
+    /// Directory that relative paths of the document are resolved against
+    pub base_directory: PathBuf,
+    /// Directory of the root document, if the current document is a template
+    root_directory: Option<PathBuf>,
+    /// Files read while lexing the document, besides the document itself
+    pub dependencies: Vec<PathBuf>,
+    /// Additional directories searched for templates (e.g. given by `--template-dir`)
+    pub template_directories: Vec<PathBuf>,
 }
 
-fn replace_template_attributes(
+impl LexerState {
+    /// Defines a variable of the current document, which is visible to all templates it calls
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        if self.scopes.is_empty() {
+            self.scopes.push(Scope::new());
+        }
+
+        self.scopes[self.declaration_scope].insert(name.to_string(), value);
+    }
+
+    /// Adds variables that shadow those set by the document (e.g. the fields of a merged record)
+    pub fn push_scope(&mut self, scope: Scope) {
+        if self.scopes.is_empty() {
+            self.scopes.push(Scope::new());
+        }
+
+        self.scopes.push(scope);
+    }
+}
+
+/// Returns the directories searched for template files, in the order of their precedence
+fn template_directories(lex_state: &LexerState) -> Vec<PathBuf> {
+    let mut directories = vec![lex_state.base_directory.clone()];
+
+    // Templates of the root document remain available to templates in other directories
+    if let Some(root_directory) = &lex_state.root_directory {
+        if *root_directory != lex_state.base_directory {
+            directories.push(root_directory.clone());
+        }
+    }
+
+    directories.extend(lex_state.template_directories.iter().cloned());
+
+    directories.extend(environment_template_directories(
+        env::var_os("TWML_TEMPLATE_PATH"),
+        env::var_os("XDG_CONFIG_HOME"),
+        env::var_os("HOME"),
+    ));
+
+    directories
+}
+
+/// Returns the template directories given by the environment variables `TWML_TEMPLATE_PATH`,
+/// `XDG_CONFIG_HOME` and `HOME`, followed by the system-wide directory
+pub(crate) fn environment_template_directories(
+    template_path: Option<OsString>,
+    config_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Vec<PathBuf> {
+    let home = home.filter(|home| !home.is_empty()).map(PathBuf::from);
+
+    // A leading `~` is expanded to the home directory, as the variable may be set without a shell
+    let expand_home = |path: PathBuf| match (path.strip_prefix("~"), &home) {
+        (Ok(relative_path), Some(home)) => home.join(relative_path),
+        _ => path,
+    };
+
+    let mut directories: Vec<PathBuf> = Vec::new();
+
+    if let Some(paths) = template_path {
+        directories.extend(
+            env::split_paths(&paths)
+                .filter(|path| !path.as_os_str().is_empty())
+                .map(expand_home),
+        );
+    }
+
+    let config_directory = config_home
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .map(expand_home)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+
+    if let Some(config_directory) = config_directory {
+        directories.push(config_directory.join("twml/templates"));
+    }
+
+    directories.push(PathBuf::from("/usr/share/twml/templates"));
+
+    directories
+}
+
+/// Returns the source of a linked script or stylesheet. Local files are resolved relative to the
+/// document and referenced relative to the root document, next to which the html is placed.
+fn link_file(lex_state: &mut LexerState, source: &str) -> Result<String> {
+    if source.contains("://") || source.starts_with("//") || source.starts_with("data:") {
+        return Ok(source.to_string());
+    }
+
+    let path = lex_state.base_directory.join(source);
+    let root_directory = lex_state
+        .root_directory
+        .as_ref()
+        .unwrap_or(&lex_state.base_directory);
+    let target = link_target(root_directory, &path)?;
+
+    lex_state
+        .declarations
+        .links
+        .push((path.clone(), target.clone()));
+    lex_state.dependencies.push(path);
+
+    Ok(target)
+}
+
+/// Returns the path of a file relative to the root directory, or its absolute path if the file is
+/// located outside of it (e.g. within a shared template directory)
+fn link_target(root_directory: &Path, path: &Path) -> Result<String> {
+    let current_directory = env::current_dir().context("Failed to read the current directory")?;
+    let root_directory = normalize_path(&current_directory.join(root_directory));
+    let path = normalize_path(&current_directory.join(path));
+
+    Ok(match path.strip_prefix(&root_directory) {
+        Ok(relative_path) => relative_path.display().to_string(),
+        Err(_) => path.display().to_string(),
+    })
+}
+
+/// Removes `.` and `..` components from a path without accessing the file system
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized_path = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized_path.pop();
+            }
+            component => normalized_path.push(component),
+        }
+    }
+
+    normalized_path
+}
+
+/// Returns the relative urls referenced by a stylesheet, e.g. of images or fonts
+fn stylesheet_urls(content: &str) -> Vec<String> {
+    content
+        .split("url(")
+        .skip(1)
+        .filter_map(|rest| {
+            let url = rest.split(')').next()?.trim().trim_matches(['"', '\'']);
+            let url = url.split(['?', '#']).next()?;
+
+            (!url.is_empty() && !url.contains(':') && !url.starts_with('/'))
+                .then(|| url.to_string())
+        })
+        .collect()
+}
+
+/// Replaces the placeholders of content that is escaped afterwards (e.g. an attribute value),
+/// reporting errors with the line of its pair
+fn replace_placeholders(
     content: &str,
-    attributes: &HashMap<String, String>,
+    pair: &Pair<Rule>,
+    lex_state: &LexerState,
 ) -> Result<String> {
-    let mut content: String = content.to_string();
+    let segments = placeholder::split(content, &lex_state.scopes)
+        .context(format!("Failed to evaluate line {}", pair.line_col().0))?;
+
+    Ok(segments
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) | Segment::Value(text) => text,
+        })
+        .collect())
+}
 
-    for (key, value) in attributes.iter() {
-        let regex = &Regex::new(&format!(r"(?!\}})\{{{}\}}(?!\}})", key))?;
-        content = regex.replace(&content, value).to_string();
+pub(crate) fn escape_html(content: &str) -> String {
+    content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attribute(value: &str) -> String {
+    escape_html(value).replace('"', "&quot;")
+}
+
+fn escape_content(lex_state: &LexerState, content: String) -> String {
+    if lex_state.raw {
+        content
+    } else {
+        escape_html(&content)
+    }
+}
+
+fn format_attributes(attributes: &[(String, Option<String>)]) -> String {
+    attributes
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => format!(" {}=\"{}\"", key, escape_attribute(value)),
+            None => format!(" {}", key),
+        })
+        .collect()
+}
+
+/// Applies the inline markup to the text written in the source only, so that the values of
+/// placeholders (e.g. from data files) are never interpreted as markup
+fn apply_markup_to_segments(
+    segments: &[Segment],
+    classes: &MarkupClasses,
+) -> Result<(String, String)> {
+    // Values are replaced by markers of private use characters, which the markup leaves untouched
+    let marker = |index: usize| format!("\u{E000}{}\u{E001}", index);
+    let mut content = String::new();
+    let mut values: Vec<&str> = Vec::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => content.push_str(text),
+            Segment::Value(value) => {
+                content.push_str(&marker(values.len()));
+                values.push(value);
+            }
+        }
     }
 
-    Ok(content)
+    let (mut html, mut text) = apply_markup(&content, classes)?;
+
+    for (index, value) in values.into_iter().enumerate() {
+        html = html.replace(&marker(index), &escape_attribute(value));
+        text = text.replace(&marker(index), value);
+    }
+
+    Ok((html, text))
+}
+
+/// Returns the html and the plain text of content written in the inline markup
+fn apply_markup(content: &str, classes: &MarkupClasses) -> Result<(String, String)> {
+    static MARKUP_REGEX: OnceLock<Regex> = OnceLock::new();
+
+    let regex = MARKUP_REGEX.get_or_init(|| {
+        Regex::new(concat!(
+            r"`([^`]+)`",
+            r"|\*(?!\s)([^*]+?)(?<!\s)\*",
+            r"|(?<!\w)_(?!\s)([^_]+?)(?<!\s)_(?!\w)",
+            r"|\[([^\]]+)\]\(([^)\s]+)\)",
+        ))
+        .unwrap()
+    });
+    let mut html = String::new();
+    let mut text = String::new();
+    let mut position = 0;
+
+    for captures in regex.captures_iter(content) {
+        let captures = captures?;
+        let markup = captures.get(0).unwrap();
+
+        html.push_str(&escape_html(&content[position..markup.start()]));
+        text.push_str(&content[position..markup.start()]);
+
+        if let Some(code) = captures.get(1) {
+            html.push_str(&format!(
+                "<code class=\"{}\">{}</code>",
+                classes.code,
+                escape_html(code.as_str())
+            ));
+            text.push_str(code.as_str());
+        } else if let Some(bold) = captures.get(2) {
+            let (inner_html, inner_text) = apply_markup(bold.as_str(), classes)?;
+
+            html.push_str(&format!(
+                "<strong class=\"{}\">{}</strong>",
+                classes.bold, inner_html
+            ));
+            text.push_str(&inner_text);
+        } else if let Some(italic) = captures.get(3) {
+            let (inner_html, inner_text) = apply_markup(italic.as_str(), classes)?;
+
+            html.push_str(&format!(
+                "<em class=\"{}\">{}</em>",
+                classes.italic, inner_html
+            ));
+            text.push_str(&inner_text);
+        } else if let (Some(link), Some(url)) = (captures.get(4), captures.get(5)) {
+            let (inner_html, inner_text) = apply_markup(link.as_str(), classes)?;
+
+            html.push_str(&format!(
+                "<a class=\"{}\" href=\"{}\">{}</a>",
+                classes.link,
+                escape_attribute(url.as_str()),
+                inner_html
+            ));
+            text.push_str(&inner_text);
+        }
+
+        position = markup.end();
+    }
+
+    html.push_str(&escape_html(&content[position..]));
+    text.push_str(&content[position..]);
+
+    Ok((html, text))
+}
+
+/// Removes the indentation that all non-empty lines have in common
+fn dedent(lines: &[&str]) -> String {
+    let indentation = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| line.get(indentation..).unwrap_or("").trim_end())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Selects a range of lines like `60-75`, `60-` or `60` and returns it along with the number of
+/// its first line
+fn select_lines(source: &str, range: &str) -> Result<(String, usize)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start = start
+        .trim()
+        .parse::<usize>()
+        .context(format!("The line range '{}' is invalid", range))?;
+    let end = match end.trim() {
+        "" => usize::MAX,
+        end => end
+            .parse::<usize>()
+            .context(format!("The line range '{}' is invalid", range))?,
+    };
+
+    if start == 0 || start > end {
+        return Err(anyhow!(format!("The line range '{}' is invalid", range)));
+    }
+
+    let lines: Vec<&str> = source
+        .lines()
+        .skip(start - 1)
+        .take((end - start).saturating_add(1))
+        .collect();
+
+    if lines.is_empty() {
+        return Err(anyhow!(format!(
+            "The line range '{}' is out of bounds",
+            range
+        )));
+    }
+
+    Ok((dedent(&lines), start))
+}
+
+/// Selects the lines between the markers `twml:region <name>` and `twml:endregion` and returns
+/// them along with the number of the first line
+fn select_region(source: &str, name: &str) -> Result<(String, usize)> {
+    let lines: Vec<&str> = source.lines().collect();
+    let marker = format!("twml:region {}", name);
+    let start = lines
+        .iter()
+        .position(|line| line.trim_end().ends_with(&marker))
+        .context(format!("Failed to find the region '{}'", name))?
+        + 1;
+    let end = lines[start..]
+        .iter()
+        .position(|line| line.contains("twml:endregion"))
+        .context(format!("The region '{}' is never closed", name))?
+        + start;
+
+    Ok((dedent(&lines[start..end]), start + 1))
+}
+
+/// Parses the value of a `@param` declaration (e.g. `title required` or `color default="slate"`)
+fn parse_parameter(value: &str) -> Result<TemplateParameter> {
+    let (name, option) = value.split_once(' ').unwrap_or((value, ""));
+    let option = option.trim();
+
+    let (required, default) = if option.is_empty() {
+        (false, None)
+    } else if option == "required" {
+        (true, None)
+    } else if let Some(default) = option.strip_prefix("default=") {
+        (
+            false,
+            Some(
+                placeholder::evaluate_value(default, &[])
+                    .context(format!("The default value of '{}' is invalid", name))?,
+            ),
+        )
+    } else {
+        return Err(anyhow!(format!(
+            "The parameter '{}' expects either 'required' or 'default=value'",
+            name
+        )));
+    };
+
+    Ok(TemplateParameter {
+        name: name.to_string(),
+        required,
+        default,
+    })
+}
+
+/// Returns whether the pair is a block element whose name starts with the prefix (e.g. `slot:`)
+fn is_prefixed_element(pair: &Pair<Rule>, prefix: &str) -> bool {
+    pair.as_rule() == Rule::block_element
+        && pair
+            .clone()
+            .into_inner()
+            .next()
+            .is_some_and(|name_pair| name_pair.as_str().starts_with(prefix))
+}
+
+fn close_empty_element(name: &str, self_closing: bool) -> String {
+    if self_closing || VOID_ELEMENTS.contains(&name) {
+        String::from(" />")
+    } else {
+        format!("></{}>", name)
+    }
 }
 
 impl DocumentParser {
@@ -68,11 +583,39 @@ impl DocumentParser {
         for file in &declarations.include {
             fs::copy(
                 file,
-                &temporary_dir.join(Path::new(file).file_name().unwrap()),
+                temporary_dir.join(Path::new(file).file_name().unwrap()),
             )
             .context(format!("Failed to include '{}'", file))?;
         }
 
+        // Linked files are copied with the layout they are referenced by, including the files
+        // their stylesheets refer to
+        for (path, target) in &declarations.links {
+            let target_path = temporary_dir.join(target.trim_start_matches('/'));
+            let mut files = vec![(path.clone(), target_path.clone())];
+
+            if path.extension() == Some(OsStr::new("css")) {
+                let content = fs::read_to_string(path)
+                    .context(format!("Failed to read '{}'", path.display()))?;
+
+                for url in stylesheet_urls(&content) {
+                    let source = path.parent().unwrap().join(&url);
+                    let target = normalize_path(&target_path.parent().unwrap().join(&url));
+
+                    if source.is_file() && target.starts_with(temporary_dir) {
+                        files.push((source, target));
+                    }
+                }
+            }
+
+            for (source, target) in files {
+                fs::create_dir_all(target.parent().unwrap())
+                    .context(format!("Failed to include '{}'", source.display()))?;
+                fs::copy(&source, &target)
+                    .context(format!("Failed to include '{}'", source.display()))?;
+            }
+        }
+
         if !temporary_dir.join("fonts").exists() {
             fs::create_dir(temporary_dir.join("fonts"))
                 .context("Failed to create directory fonts")?;
@@ -80,7 +623,7 @@ impl DocumentParser {
 
         for font in &declarations.fonts {
             fs::write(
-                &temporary_dir.join(format!(
+                temporary_dir.join(format!(
                     "fonts/{}",
                     font.postscript_name()
                         .unwrap()
@@ -98,6 +641,24 @@ impl DocumentParser {
         Ok(())
     }
 
+    /// Returns the parameters declared by a template, e.g. to document its interface
+    pub fn template_parameters(pairs: Pairs<Rule>) -> Result<Vec<TemplateParameter>> {
+        let mut parameters: Vec<TemplateParameter> = Vec::new();
+
+        for pair in pairs
+            .flatten()
+            .filter(|pair| pair.as_rule() == Rule::declaration)
+        {
+            let mut iterator = pair.into_inner();
+
+            if iterator.next().unwrap().as_str() == "param" {
+                parameters.push(parse_parameter(iterator.next().unwrap().as_str())?);
+            }
+        }
+
+        Ok(parameters)
+    }
+
     pub fn generate_html(lex_state: &mut LexerState, pairs: Pairs<Rule>) -> Result<String> {
         let html_tokens = Self::lex_html_document(lex_state, pairs)?;
         let html_body = Self::generate_html_body(&html_tokens, 4, "")?;
@@ -185,6 +746,7 @@ impl DocumentParser {
     ) -> Result<String> {
         let mut html = String::new();
         let mut element_unclosed = false;
+        let mut element_self_closing = false;
         let mut element_name = element_name;
         let mut following_block_line = false;
 
@@ -192,28 +754,33 @@ impl DocumentParser {
             match token {
                 HtmlToken::ElementName { name } => {
                     if element_unclosed {
-                        html.push_str(" />");
+                        html.push_str(&close_empty_element(element_name, element_self_closing));
                     }
 
                     html.push_str(&format!("\n{}<{}", " ".repeat(indentation), name));
 
                     element_name = name;
                     element_unclosed = true;
+                    element_self_closing = false;
                     following_block_line = false;
                 }
                 HtmlToken::ElementClasses { classes } => {
-                    html.push_str(&format!(" class=\"{}\"", classes.join(" ")));
+                    html.push_str(&format!(
+                        " class=\"{}\"",
+                        escape_attribute(&classes.join(" "))
+                    ));
                 }
                 HtmlToken::ElementAttributes { attributes } => {
-                    for (key, value) in attributes.iter() {
-                        html.push_str(&format!(" {}=\"{}\"", key, value));
-                    }
+                    html.push_str(&format_attributes(attributes));
                 }
                 HtmlToken::ElementInlineContent { content } => {
                     html.push_str(&format!(">{}</{}>", content, element_name));
 
                     element_unclosed = false;
                 }
+                HtmlToken::ElementSelfClosing => {
+                    element_self_closing = true;
+                }
                 HtmlToken::ElementChildren { children } => {
                     html.push('>');
                     html.push_str(&Self::generate_html_body(
@@ -242,7 +809,7 @@ impl DocumentParser {
                 }
                 HtmlToken::EmptyBlockLine => {
                     if element_unclosed {
-                        html.push_str(" />");
+                        html.push_str(&close_empty_element(element_name, element_self_closing));
                         element_unclosed = false;
                     }
 
@@ -256,7 +823,7 @@ impl DocumentParser {
         }
 
         if element_unclosed {
-            html.push_str(" />");
+            html.push_str(&close_empty_element(element_name, element_self_closing));
         }
 
         Ok(html)
@@ -267,20 +834,84 @@ impl DocumentParser {
         mut pairs: Pairs<Rule>,
     ) -> Result<Vec<HtmlToken>> {
         let mut html: Vec<HtmlToken> = Vec::new();
+        let mut blocks: LayoutBlocks = HashMap::new();
 
         for pair in pairs.next().unwrap().into_inner() {
             match pair.as_rule() {
                 Rule::declaration => Self::lex_html_declaration(lex_state, pair)
                     .context("Failed to parse a declaration")?,
+                Rule::definition => Self::lex_html_definition(lex_state, pair)?,
+                Rule::block if lex_state.layout.is_some() => {
+                    for pair in pair.into_inner() {
+                        if pair.as_rule() == Rule::block_content_empty_line {
+                            continue;
+                        }
+
+                        if !is_prefixed_element(&pair, "block:") {
+                            return Err(anyhow!(format!(
+                                "Only blocks may follow the declarations of a layout's child, found line {}",
+                                pair.line_col().0
+                            )));
+                        }
+
+                        let (name, block) = Self::lex_html_block_override(lex_state, pair)?;
+
+                        if blocks.insert(name.clone(), block).is_some() {
+                            return Err(anyhow!(format!(
+                                "The block '{}' is overridden more than once",
+                                name
+                            )));
+                        }
+                    }
+                }
                 Rule::block => html.extend(Self::lex_html_block(lex_state, pair.into_inner())?),
                 Rule::EOI => {}
                 _ => return Err(anyhow!(format!("Unexpected document rule: {:?}", pair))),
             }
         }
 
+        if let Some(layout) = lex_state.layout.take() {
+            // Blocks overridden by a document extending this one take precedence over its own,
+            // while those this document has not inserted are passed on to its layout
+            blocks.extend(
+                lex_state
+                    .layout_blocks
+                    .take()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|(_, block)| !block.used),
+            );
+
+            let mut template = Self::find_template(lex_state, &layout)?;
+            template.blocks = Some(blocks);
+
+            return Self::lex_html_template(lex_state, template);
+        }
+
         Ok(html)
     }
 
+    /// Stores the source of a template defined within the document
+    fn lex_html_definition(lex_state: &mut LexerState, pair: Pair<Rule>) -> Result<()> {
+        let mut pairs = pair.into_inner();
+        let name = pairs.next().unwrap().as_str().to_string();
+
+        if BUILTIN_TEMPLATES.contains(&name.as_str()) {
+            return Err(anyhow!(format!(
+                "The template name '{}' is reserved for a builtin template",
+                name
+            )));
+        }
+
+        let source = Self::lex_html_verbatim(pairs)?;
+        let directory = lex_state.base_directory.clone();
+        lex_state
+            .definitions
+            .insert(name, (source + "\n", directory));
+
+        Ok(())
+    }
+
     fn lex_html_declaration(lex_state: &mut LexerState, pair: Pair<Rule>) -> Result<()> {
         let mut iterator = pair.into_inner();
         let declaration_key_pair = iterator.next().unwrap();
@@ -296,10 +927,15 @@ impl DocumentParser {
         let declaration_value = declaration_value_pair.as_span().as_str();
 
         match declaration_key {
-            "include" => lex_state
-                .declarations
-                .include
-                .push(declaration_value.to_string()),
+            "include" => {
+                let path = lex_state.base_directory.join(declaration_value);
+
+                lex_state
+                    .declarations
+                    .include
+                    .push(path.display().to_string());
+                lex_state.dependencies.push(path);
+            }
             "page-width" => {
                 lex_state.declarations.page_width_mm = Some(
                     declaration_value
@@ -322,14 +958,65 @@ impl DocumentParser {
                         .load()?,
                 );
             }
-            "js" => lex_state
-                .declarations
-                .js
-                .push(declaration_value.to_string()),
-            "css" => lex_state
-                .declarations
-                .css
-                .push(declaration_value.to_string()),
+            "js" => {
+                let source = link_file(lex_state, declaration_value)?;
+                lex_state.declarations.js.push(source);
+            }
+            "css" => {
+                let source = link_file(lex_state, declaration_value)?;
+                lex_state.declarations.css.push(source);
+            }
+            "markup" => {
+                lex_state.declarations.markup = match declaration_value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(anyhow!("The markup value must be either 'on' or 'off'")),
+                }
+            }
+            "markup-bold" => {
+                lex_state.declarations.markup_classes.bold = declaration_value.to_string()
+            }
+            "markup-italic" => {
+                lex_state.declarations.markup_classes.italic = declaration_value.to_string()
+            }
+            "markup-code" => {
+                lex_state.declarations.markup_classes.code = declaration_value.to_string()
+            }
+            "markup-link" => {
+                lex_state.declarations.markup_classes.link = declaration_value.to_string()
+            }
+            "set" => {
+                let (key, value) = declaration_value
+                    .split_once(' ')
+                    .context("The set declaration expects a name and a value")?;
+
+                let value = value.trim();
+                // Values in brackets are lists (e.g. `["a", "b"]`), anything else is text
+                let value = if placeholder::is_list(value) {
+                    placeholder::evaluate_value(value, &lex_state.scopes)?
+                } else {
+                    Value::String(value.to_string())
+                };
+
+                lex_state.set_variable(key, value);
+            }
+            "data" => {
+                let (key, path) = declaration_value
+                    .split_once(' ')
+                    .context("The data declaration expects a name and a path")?;
+                let path = lex_state.base_directory.join(path.trim());
+
+                lex_state.set_variable(key, data::load(&path)?);
+                lex_state.dependencies.push(path);
+            }
+            "code-theme" => lex_state.declarations.code_theme = Some(declaration_value.to_string()),
+            "extends" => lex_state.layout = Some(declaration_value.to_string()),
+            "param" => {
+                // Parameters are checked when the template is called
+                if lex_state.template_children.is_none() {
+                    return Err(anyhow!("Parameters may only be declared by templates"));
+                }
+            }
             _ => {
                 return Err(anyhow!(format!(
                     "The declaration key '{}' is unexpected",
@@ -341,26 +1028,66 @@ impl DocumentParser {
         Ok(())
     }
 
-    fn lex_html_block(lex_state: &mut LexerState, pairs: Pairs<Rule>) -> Result<Vec<HtmlToken>> {
+    fn lex_html_block<'i>(
+        lex_state: &mut LexerState,
+        pairs: impl Iterator<Item = Pair<'i, Rule>>,
+    ) -> Result<Vec<HtmlToken>> {
         let mut html: Vec<HtmlToken> = Vec::new();
+        // Whether a branch of the preceding conditional block has been emitted, if any
+        let mut conditional: Option<bool> = None;
 
         for pair in pairs {
+            if pair.as_rule() == Rule::block_content_empty_line {
+                html.push(HtmlToken::EmptyBlockLine);
+                continue;
+            }
+
+            let preceding_conditional = conditional.take();
+
             match pair.as_rule() {
-                Rule::block_element => html.extend(Self::lex_html_block_element(lex_state, pair)?),
-                Rule::block_template => {
-                    html.extend(Self::lex_html_block_template(lex_state, pair)?)
+                Rule::block_element if is_prefixed_element(&pair, "block:") => {
+                    html.extend(Self::lex_html_layout_block(lex_state, pair)?)
                 }
+                // Slots of a template call are taken from its children before they are lexed
+                Rule::block_element if is_prefixed_element(&pair, "slot:") => {
+                    return Err(anyhow!(format!(
+                        "The slot on line {} is not a direct child of a template call",
+                        pair.line_col().0
+                    )))
+                }
+                Rule::block_element => html.extend(Self::lex_html_block_element(lex_state, pair)?),
+                Rule::block_template => match pair.clone().into_inner().next().unwrap().as_str() {
+                    "if" => {
+                        let (tokens, emitted) =
+                            Self::lex_html_block_conditional(lex_state, pair, false)?;
+
+                        html.extend(tokens);
+                        conditional = Some(emitted);
+                    }
+                    "else" => {
+                        let emitted = preceding_conditional
+                            .context("An else block must directly follow an if block")?;
+                        let chained = pair
+                            .clone()
+                            .into_inner()
+                            .any(|pair| pair.as_rule() == Rule::block_template_attributes);
+                        let (tokens, emitted) =
+                            Self::lex_html_block_conditional(lex_state, pair, emitted)?;
+
+                        html.extend(tokens);
+
+                        // An else block with a condition may be followed by further else blocks
+                        if chained {
+                            conditional = Some(emitted);
+                        }
+                    }
+                    _ => html.extend(Self::lex_html_block_template(lex_state, pair)?),
+                },
                 Rule::block_content_line => {
                     html.push(HtmlToken::BlockLine {
-                        content: replace_template_attributes(
-                            pair.as_span().as_str(),
-                            &lex_state.template_attributes,
-                        )?,
+                        content: Self::lex_html_inline(lex_state, pair)?.0,
                     });
                 }
-                Rule::block_content_empty_line => {
-                    html.push(HtmlToken::EmptyBlockLine);
-                }
                 _ => return Err(anyhow!(format!("Unexpected block rule: {:?}", pair))),
             }
         }
@@ -368,6 +1095,89 @@ impl DocumentParser {
         Ok(html)
     }
 
+    /// Lexes the body of an if or else block if its condition holds and no previous branch has
+    /// been emitted, returning whether any branch has been emitted so far
+    fn lex_html_block_conditional(
+        lex_state: &mut LexerState,
+        conditional_pair: Pair<Rule>,
+        emitted: bool,
+    ) -> Result<(Vec<HtmlToken>, bool)> {
+        let mut html: Vec<HtmlToken> = Vec::new();
+        let mut pairs = conditional_pair.into_inner();
+        let name_pair = pairs.next().unwrap();
+        let mut condition: Option<String> = None;
+        let mut matched = !emitted;
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::block_template_attributes => {
+                    for (key, value) in Self::lex_html_attributes(lex_state, pair.into_inner())? {
+                        match (key.as_str(), value) {
+                            ("cond", Some(value)) => condition = Some(value),
+                            _ => {
+                                return Err(anyhow!(format!(
+                                    "The {} block only accepts the attribute 'cond', found '{}'",
+                                    name_pair.as_str(),
+                                    key
+                                )))
+                            }
+                        }
+                    }
+
+                    if let (true, Some(condition)) = (matched, &condition) {
+                        matched = placeholder::evaluate_condition(condition, &lex_state.scopes)
+                            .context(format!(
+                                "Failed to evaluate the condition on line {}",
+                                name_pair.line_col().0
+                            ))?;
+                    }
+                }
+                Rule::block_children | Rule::block_template_content | Rule::block_verbatim => {
+                    if matched {
+                        html.extend(Self::lex_html_body(lex_state, pair)?);
+                    }
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "The {} block does not accept classes: {:?}",
+                        name_pair.as_str(),
+                        pair
+                    )))
+                }
+            }
+        }
+
+        if name_pair.as_str() == "if" && condition.is_none() {
+            return Err(anyhow!(format!(
+                "The if block on line {} expects a condition (e.g. cond=\"title\")",
+                name_pair.line_col().0
+            )));
+        }
+
+        Ok((html, emitted || matched))
+    }
+
+    /// Lexes the body of a block that is not an element itself (e.g. of a template or an if block),
+    /// which is either its children, its inline content or a verbatim body
+    fn lex_html_body(lex_state: &mut LexerState, pair: Pair<Rule>) -> Result<Vec<HtmlToken>> {
+        match pair.as_rule() {
+            Rule::block_children => Self::lex_html_block_children(lex_state, pair.into_inner()),
+            Rule::block_template_content | Rule::block_element_content => {
+                Ok(vec![HtmlToken::BlockLine {
+                    content: Self::lex_html_inline(lex_state, pair)?.0,
+                }])
+            }
+            Rule::block_verbatim => {
+                let content = Self::lex_html_verbatim(pair.into_inner())?;
+
+                Ok(vec![HtmlToken::BlockLine {
+                    content: escape_content(lex_state, content),
+                }])
+            }
+            _ => Err(anyhow!(format!("Unexpected block body rule: {:?}", pair))),
+        }
+    }
+
     fn lex_html_block_children(
         lex_state: &mut LexerState,
         pairs: Pairs<Rule>,
@@ -377,7 +1187,28 @@ impl DocumentParser {
         for pair in pairs {
             match pair.as_rule() {
                 Rule::block => html.extend(Self::lex_html_block(lex_state, pair.into_inner())?),
-                Rule::ellipsis => html.extend(lex_state.template_children.clone().unwrap()),
+                Rule::ellipsis => match pair.into_inner().collect::<Vec<Pair<Rule>>>().as_slice() {
+                    [slot_name_pair, optional_pairs @ ..] => {
+                        let slot_name = slot_name_pair.as_str();
+                        let optional = !optional_pairs.is_empty();
+
+                        match lex_state
+                            .template_slots
+                            .as_ref()
+                            .and_then(|slots| slots.get(slot_name))
+                        {
+                            Some(slot) => html.extend(slot.clone()),
+                            None if optional => {}
+                            None => {
+                                return Err(anyhow!(format!(
+                                    "The slot '{}' has not been provided (e.g. by \\slot:{})",
+                                    slot_name, slot_name
+                                )))
+                            }
+                        }
+                    }
+                    [] => html.extend(lex_state.template_children.clone().unwrap()),
+                },
                 _ => {
                     return Err(anyhow!(format!(
                         "Unexpected block children rule: {:?}",
@@ -390,36 +1221,352 @@ impl DocumentParser {
         Ok(html)
     }
 
+    /// Lexes the children of a template call, separating the contents of named slots
+    /// (`\slot:name`) from the remaining children
+    fn lex_html_template_children(
+        lex_state: &mut LexerState,
+        pairs: Pairs<Rule>,
+    ) -> Result<(Vec<HtmlToken>, Slots)> {
+        let mut slots: Slots = HashMap::new();
+        let mut children: Vec<Pair<Rule>> = Vec::new();
+
+        if pairs.clone().any(|pair| pair.as_rule() != Rule::block) {
+            return Ok((Self::lex_html_block_children(lex_state, pairs)?, slots));
+        }
+
+        for pair in pairs.flat_map(|pair| pair.into_inner()) {
+            if !is_prefixed_element(&pair, "slot:") {
+                children.push(pair);
+                continue;
+            }
+
+            let (slot_name, slot) = Self::lex_html_named_block(lex_state, pair)?;
+
+            if slots.insert(slot_name.clone(), slot).is_some() {
+                return Err(anyhow!(format!(
+                    "The slot '{}' is provided more than once",
+                    slot_name
+                )));
+            }
+        }
+
+        Ok((
+            Self::lex_html_block(lex_state, children.into_iter())?,
+            slots,
+        ))
+    }
+
+    /// Lexes the content of a named element such as `\slot:name` or `\block:name`, returning its
+    /// name without the prefix
+    fn lex_html_named_block(
+        lex_state: &mut LexerState,
+        named_pair: Pair<Rule>,
+    ) -> Result<(String, Vec<HtmlToken>)> {
+        let mut html: Vec<HtmlToken> = Vec::new();
+        let mut pairs = named_pair.into_inner();
+        let element_name = pairs.next().unwrap().as_str();
+        let name = element_name.split_once(':').unwrap().1.to_string();
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::block_children | Rule::block_element_content | Rule::block_verbatim => {
+                    html.extend(Self::lex_html_body(lex_state, pair)?);
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "The element '\\{}' does not accept an id, classes or attributes",
+                        element_name
+                    )))
+                }
+            }
+        }
+
+        Ok((name, html))
+    }
+
+    /// Lexes a block overriding one of the layout, with its pages counted from the block's start
+    fn lex_html_block_override(
+        lex_state: &mut LexerState,
+        named_pair: Pair<Rule>,
+    ) -> Result<(String, LayoutBlock)> {
+        let page_number = mem::replace(&mut lex_state.page_number, 0);
+        let toc = mem::take(&mut lex_state.toc);
+        let result = Self::lex_html_named_block(lex_state, named_pair);
+        let pages = mem::replace(&mut lex_state.page_number, page_number);
+        let block_toc = mem::replace(&mut lex_state.toc, toc);
+        let (name, html) = result?;
+
+        Ok((
+            name,
+            LayoutBlock {
+                html,
+                pages,
+                toc: block_toc,
+                used: false,
+            },
+        ))
+    }
+
+    /// Lexes a block of a layout, unless it has been overridden by the extending document
+    fn lex_html_layout_block(
+        lex_state: &mut LexerState,
+        block_pair: Pair<Rule>,
+    ) -> Result<Vec<HtmlToken>> {
+        let element_name = block_pair.clone().into_inner().next().unwrap();
+        let name = &element_name.as_str()["block:".len()..];
+
+        if let Some(block) = lex_state
+            .layout_blocks
+            .as_mut()
+            .and_then(|blocks| blocks.get_mut(name))
+        {
+            block.used = true;
+
+            // The pages of the block follow those of the layout preceding it
+            for (text, page) in &block.toc {
+                lex_state
+                    .toc
+                    .push((text.clone(), lex_state.page_number + page));
+            }
+
+            lex_state.page_number += block.pages;
+
+            return Ok(block.html.clone());
+        }
+
+        Ok(Self::lex_html_named_block(lex_state, block_pair)?.1)
+    }
+
+    fn lex_html_attributes(
+        lex_state: &LexerState,
+        pairs: Pairs<Rule>,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        let mut attributes: Vec<(String, Option<String>)> = Vec::new();
+
+        for attribute_pair in pairs {
+            if attribute_pair.as_rule() != Rule::attribute {
+                return Err(anyhow!("Unexpected attributes rule"));
+            }
+
+            let mut iterator = attribute_pair.into_inner();
+            let attribute_key_pair = iterator.next().unwrap();
+            let attribute_value_pair = iterator.next();
+
+            if attribute_key_pair.as_rule() != Rule::attribute_key
+                || attribute_value_pair
+                    .as_ref()
+                    .is_some_and(|pair| pair.as_rule() != Rule::attribute_value)
+            {
+                return Err(anyhow!("Attribute pairs out of order"));
+            }
+
+            let attribute_value = match attribute_value_pair {
+                Some(pair) => Some(replace_placeholders(
+                    &pair
+                        .as_span()
+                        .as_str()
+                        .replace("\\\"", "\"")
+                        .replace("\\'", "'"),
+                    &pair,
+                    lex_state,
+                )?),
+                None => None,
+            };
+
+            attributes.push((
+                attribute_key_pair.as_span().as_str().to_string(),
+                attribute_value,
+            ));
+        }
+
+        Ok(attributes)
+    }
+
+    fn lex_html_classes(lex_state: &LexerState, pairs: Pairs<Rule>) -> Result<Vec<String>> {
+        let mut classes: Vec<String> = Vec::new();
+
+        for class_pair in pairs {
+            match class_pair.as_rule() {
+                Rule::block_element_class | Rule::block_template_class => classes.push(
+                    replace_placeholders(class_pair.as_span().as_str(), &class_pair, lex_state)?,
+                ),
+                Rule::extend_classes => classes.extend(lex_state.template_classes.clone().unwrap()),
+                _ => return Err(anyhow!("Unexpected classes rule")),
+            }
+        }
+
+        Ok(classes)
+    }
+
+    fn lex_html_id(lex_state: &LexerState, id_pair: Pair<Rule>) -> Result<String> {
+        let element_id_pair = id_pair.into_inner().next().unwrap();
+
+        replace_placeholders(element_id_pair.as_str(), &element_id_pair, lex_state)
+    }
+
+    /// Returns the html and the plain text of a line that may contain inline elements
+    fn lex_html_inline(lex_state: &mut LexerState, pair: Pair<Rule>) -> Result<(String, String)> {
+        if lex_state.raw {
+            let content = placeholder::interpolate(pair.as_span().as_str(), &lex_state.scopes)
+                .context(format!("Failed to evaluate line {}", pair.line_col().0))?;
+
+            return Ok((content.clone(), content));
+        }
+
+        let mut html = String::new();
+        let mut text = String::new();
+
+        for inline_pair in pair.into_inner() {
+            match inline_pair.as_rule() {
+                Rule::inline_text | Rule::inline_element_text => {
+                    let segments =
+                        placeholder::split(inline_pair.as_span().as_str(), &lex_state.scopes)
+                            .context(format!(
+                                "Failed to evaluate line {}",
+                                inline_pair.line_col().0
+                            ))?;
+
+                    if lex_state.declarations.markup {
+                        let (markup_html, markup_text) = apply_markup_to_segments(
+                            &segments,
+                            &lex_state.declarations.markup_classes,
+                        )?;
+
+                        html.push_str(&markup_html);
+                        text.push_str(&markup_text);
+                    } else {
+                        for segment in segments {
+                            let (Segment::Text(content) | Segment::Value(content)) = segment;
+
+                            html.push_str(&escape_html(&content));
+                            text.push_str(&content);
+                        }
+                    }
+                }
+                Rule::inline_escape => {
+                    // An escaped inline element (e.g. `\\sqrt[3]`) is kept as written, without
+                    // the leading backslash
+                    let content = &inline_pair.as_str()[1..];
+
+                    html.push_str(&escape_html(content));
+                    text.push_str(content);
+                }
+                Rule::inline_element => {
+                    let (element_html, element_text) =
+                        Self::lex_html_inline_element(lex_state, inline_pair)?;
+
+                    html.push_str(&element_html);
+                    text.push_str(&element_text);
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "Unexpected inline rule: {:?}",
+                        inline_pair
+                    )))
+                }
+            }
+        }
+
+        Ok((html, text))
+    }
+
+    fn lex_html_inline_element(
+        lex_state: &mut LexerState,
+        element_pair: Pair<Rule>,
+    ) -> Result<(String, String)> {
+        let mut html = String::new();
+        let mut text = String::new();
+        let mut name = "";
+
+        for pair in element_pair.into_inner() {
+            match pair.as_rule() {
+                Rule::block_element_name => {
+                    name = pair.as_span().as_str();
+                    html.push_str(&format!("<{}", name));
+                }
+                Rule::block_element_id => {
+                    html.push_str(&format_attributes(&[(
+                        String::from("id"),
+                        Some(Self::lex_html_id(lex_state, pair)?),
+                    )]));
+                }
+                Rule::block_element_classes => {
+                    let classes = Self::lex_html_classes(lex_state, pair.into_inner())?;
+
+                    html.push_str(&format!(
+                        " class=\"{}\"",
+                        escape_attribute(&classes.join(" "))
+                    ));
+                }
+                Rule::block_element_attributes => {
+                    html.push_str(&format_attributes(&Self::lex_html_attributes(
+                        lex_state,
+                        pair.into_inner(),
+                    )?));
+                }
+                Rule::inline_element_content => {
+                    let (content, content_text) = Self::lex_html_inline(lex_state, pair)?;
+
+                    if content.is_empty() {
+                        html.push_str(&close_empty_element(name, false));
+                    } else {
+                        html.push_str(&format!(">{}</{}>", content, name));
+                    }
+
+                    text = content_text;
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "Unexpected inline element rule: {:?}",
+                        pair
+                    )))
+                }
+            }
+        }
+
+        Ok((html, text))
+    }
+
+    /// Returns the lines of a verbatim block exactly as written, without the block indentation
+    fn lex_html_verbatim(pairs: Pairs<Rule>) -> Result<String> {
+        let mut lines: Vec<&str> = Vec::new();
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::block_verbatim_line => lines.push(pair.as_span().as_str()),
+                Rule::block_verbatim_empty_line => lines.push(""),
+                _ => return Err(anyhow!(format!("Unexpected verbatim rule: {:?}", pair))),
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+
     fn lex_html_block_element(
         lex_state: &mut LexerState,
         element_pair: Pair<Rule>,
     ) -> Result<Vec<HtmlToken>> {
         let mut html: Vec<HtmlToken> = Vec::new();
         let mut toc = false;
+        let mut id = false;
+        // The content of raw text elements is not parsed as html by browsers
+        let mut raw_text = false;
 
         for pair in element_pair.into_inner() {
             match pair.as_rule() {
                 Rule::block_element_name => {
+                    let name = pair.as_span().as_str();
+
                     html.push(HtmlToken::ElementName {
-                        name: pair.as_span().as_str().to_string(),
+                        name: name.to_string(),
                     });
 
                     toc = false;
+                    id = false;
+                    raw_text = name == "script" || name == "style";
                 }
                 Rule::block_element_classes => {
-                    let mut classes: Vec<String> = Vec::new();
-
-                    for class_pair in pair.into_inner() {
-                        match class_pair.as_rule() {
-                            Rule::block_element_class => {
-                                classes.push(class_pair.as_span().as_str().to_string())
-                            }
-                            Rule::extend_classes => {
-                                classes.extend(lex_state.template_classes.clone().unwrap())
-                            }
-                            _ => return Err(anyhow!("Unexpected element_classes rule")),
-                        }
-                    }
+                    let classes = Self::lex_html_classes(lex_state, pair.into_inner())?;
 
                     if classes.iter().any(|class| class.as_str() == "toc") {
                         toc = true;
@@ -431,50 +1578,59 @@ impl DocumentParser {
 
                     html.push(HtmlToken::ElementClasses { classes });
                 }
-                Rule::block_element_attributes => {
-                    let mut attributes: HashMap<String, String> = HashMap::new();
-
-                    for attribute_pair in pair.into_inner() {
-                        if attribute_pair.as_rule() != Rule::attribute {
-                            return Err(anyhow!("Unexpected block_element_attributes rule"));
-                        }
-
-                        let mut iterator = attribute_pair.into_inner();
-                        let attribute_key_pair = iterator.next().unwrap();
-                        let attribute_value_pair = iterator.next().unwrap();
+                Rule::block_element_id => {
+                    html.push(HtmlToken::ElementAttributes {
+                        attributes: vec![(
+                            String::from("id"),
+                            Some(Self::lex_html_id(lex_state, pair)?),
+                        )],
+                    });
 
-                        if attribute_key_pair.as_rule() != Rule::attribute_key
-                            || attribute_value_pair.as_rule() != Rule::attribute_value
-                        {
-                            return Err(anyhow!("Attribute pairs out of order"));
-                        }
+                    id = true;
+                }
+                Rule::block_element_attributes => {
+                    let attributes = Self::lex_html_attributes(lex_state, pair.into_inner())?;
 
-                        attributes.insert(
-                            attribute_key_pair.as_span().as_str().to_string(),
-                            attribute_value_pair
-                                .as_span()
-                                .as_str()
-                                .replace("\\\"", "&quot;"),
-                        );
+                    if id && attributes.iter().any(|(key, _)| key == "id") {
+                        return Err(anyhow!("The element id is declared twice"));
                     }
 
                     html.push(HtmlToken::ElementAttributes { attributes });
                 }
                 Rule::block_element_content => {
-                    let content = replace_template_attributes(
-                        pair.as_span().as_str(),
-                        &lex_state.template_attributes,
-                    )?;
+                    let raw = lex_state.raw;
+                    lex_state.raw |= raw_text;
+                    let (content, text) = Self::lex_html_inline(lex_state, pair)?;
+                    lex_state.raw = raw;
 
                     if toc {
-                        lex_state.toc.push((content.clone(), lex_state.page_number))
+                        lex_state.toc.push((text, lex_state.page_number))
                     }
 
                     html.push(HtmlToken::ElementInlineContent { content });
                 }
+                Rule::block_element_self_closing => {
+                    html.push(HtmlToken::ElementSelfClosing);
+                }
+                Rule::block_verbatim => {
+                    let content = Self::lex_html_verbatim(pair.into_inner())?;
+
+                    html.push(HtmlToken::ElementInlineContent {
+                        content: if raw_text {
+                            content
+                        } else {
+                            escape_content(lex_state, content)
+                        },
+                    });
+                }
                 Rule::block_children => {
+                    let raw = lex_state.raw;
+                    lex_state.raw |= raw_text;
+                    let children = Self::lex_html_block_children(lex_state, pair.into_inner());
+                    lex_state.raw = raw;
+
                     html.push(HtmlToken::ElementChildren {
-                        children: Self::lex_html_block_children(lex_state, pair.into_inner())?,
+                        children: children?,
                     });
                 }
                 _ => {
@@ -489,98 +1645,290 @@ impl DocumentParser {
         Ok(html)
     }
 
-    fn lex_html_block_template(
+    fn lex_html_block_raw(
         lex_state: &mut LexerState,
-        template_pair: Pair<Rule>,
+        raw_pair: Pair<Rule>,
     ) -> Result<Vec<HtmlToken>> {
         let mut html: Vec<HtmlToken> = Vec::new();
-        let mut template_path = String::new();
-        let mut template_content = String::new();
-        let mut template_children: Vec<HtmlToken> = Vec::new();
-        let mut template_classes: Vec<String> = Vec::new();
-        let mut template_attributes: HashMap<String, String> = HashMap::new();
+        let raw = lex_state.raw;
 
-        for pair in template_pair.into_inner() {
+        lex_state.raw = true;
+
+        for pair in raw_pair.into_inner() {
             match pair.as_rule() {
-                Rule::block_template_name => {
-                    template_path = pair.as_span().as_str().replace('-', "/");
-                    let mut content: Option<String> = None;
-
-                    for directory in &[
-                        "./",
-                        "/usr/share/twml/templates/",
-                        "~/.config/twml/templates/",
-                    ] {
-                        content = fs::read_to_string(
-                            PathBuf::from(directory)
-                                .join(&template_path)
-                                .with_extension("twml"),
-                        )
-                        .ok();
-
-                        if content.is_some() {
-                            break;
-                        }
-                    }
+                Rule::block_template_name => {}
+                Rule::block_children | Rule::block_template_content | Rule::block_verbatim => {
+                    html.extend(Self::lex_html_body(lex_state, pair)?);
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "The raw block does not accept classes or attributes: {:?}",
+                        pair
+                    )))
+                }
+            }
+        }
 
-                    if content.is_none() {
-                        return Err(anyhow!(format!(
-                            "Failed to read template '{}'",
-                            template_path
-                        )));
-                    }
+        lex_state.raw = raw;
 
-                    template_content = content.unwrap();
-                }
+        Ok(html)
+    }
+
+    fn lex_html_block_code(
+        lex_state: &mut LexerState,
+        code_pair: Pair<Rule>,
+    ) -> Result<Vec<HtmlToken>> {
+        let mut html: Vec<HtmlToken> = vec![HtmlToken::ElementName {
+            name: String::from("pre"),
+        }];
+        let mut code = String::new();
+        let mut language: Option<String> = None;
+        let mut theme: Option<String> = None;
+        let mut source_path: Option<String> = None;
+        let mut lines: Option<String> = None;
+        let mut region: Option<String> = None;
+        let mut numbers = false;
+        let mut first_line_number = 1;
+
+        for pair in code_pair.into_inner() {
+            match pair.as_rule() {
+                Rule::block_template_name => {}
                 Rule::block_template_classes => {
-                    for class_pair in pair.into_inner() {
-                        match class_pair.as_rule() {
-                            Rule::block_template_class => {
-                                template_classes.push(class_pair.as_span().as_str().to_string());
-                            }
-                            Rule::extend_classes => {
-                                template_classes.extend(lex_state.template_classes.clone().unwrap())
+                    html.push(HtmlToken::ElementClasses {
+                        classes: Self::lex_html_classes(lex_state, pair.into_inner())?,
+                    });
+                }
+                Rule::block_template_attributes => {
+                    for (key, value) in Self::lex_html_attributes(lex_state, pair.into_inner())? {
+                        match key.as_str() {
+                            "lang" => language = value,
+                            "theme" => theme = value,
+                            "src" => source_path = value,
+                            "lines" => lines = value,
+                            "region" => region = value,
+                            "numbers" => numbers = true,
+                            _ => {
+                                return Err(anyhow!(format!(
+                                    "The code block does not accept the attribute '{}'",
+                                    key
+                                )))
                             }
-                            _ => return Err(anyhow!("Unexpected template_classes rule")),
                         }
                     }
                 }
+                Rule::block_template_content => code = pair.as_span().as_str().to_string(),
+                Rule::block_verbatim => code = Self::lex_html_verbatim(pair.into_inner())?,
+                _ => {
+                    return Err(anyhow!(
+                        "The code block expects inline content or a verbatim body"
+                    ))
+                }
+            }
+        }
+
+        if let Some(source_path) = source_path {
+            if !code.is_empty() {
+                return Err(anyhow!(
+                    "The code block cannot have both a source file and content"
+                ));
+            }
+
+            let path = lex_state.base_directory.join(&source_path);
+            let source = fs::read_to_string(&path).context(format!(
+                "Failed to read the source file '{}'",
+                path.display()
+            ))?;
+
+            (code, first_line_number) = match (lines, region) {
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!(
+                        "The code block cannot select both lines and a region"
+                    ))
+                }
+                (Some(lines), None) => select_lines(&source, &lines)?,
+                (None, Some(region)) => select_region(&source, &region)?,
+                (None, None) => (source.trim_end().to_string(), 1),
+            };
+
+            // Files of languages that cannot be highlighted are shown as plain text
+            if language.is_none() {
+                language = Path::new(&source_path)
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_string())
+                    .filter(|extension| highlight::is_known_language(extension));
+            }
+
+            lex_state.dependencies.push(path);
+        } else if lines.is_some() || region.is_some() {
+            return Err(anyhow!(
+                "The code block can only select lines or a region of a source file"
+            ));
+        }
+
+        let (mut lines, background) = highlight::highlight_lines(
+            &code,
+            language.as_deref(),
+            theme
+                .as_deref()
+                .or(lex_state.declarations.code_theme.as_deref())
+                .unwrap_or(highlight::DEFAULT_THEME),
+        )?;
+
+        if let Some(background) = background {
+            html.push(HtmlToken::ElementAttributes {
+                attributes: vec![(
+                    String::from("style"),
+                    Some(format!("background-color: {};", background)),
+                )],
+            });
+        }
+
+        if numbers {
+            let width = (first_line_number + lines.len() - 1).to_string().len();
+
+            for (index, line) in lines.iter_mut().enumerate() {
+                *line = format!(
+                    "<span style=\"user-select: none; opacity: 0.5;\">{:>width$} </span>{}",
+                    first_line_number + index,
+                    line,
+                    width = width
+                );
+            }
+        }
+
+        html.push(HtmlToken::ElementInlineContent {
+            content: lines.concat(),
+        });
+
+        Ok(html)
+    }
+
+    /// Lexes the body of a for block once per item of a list, which is bound to the variable
+    /// named by `item` (and its position to the variable named by `index`)
+    fn lex_html_block_for(
+        lex_state: &mut LexerState,
+        for_pair: Pair<Rule>,
+    ) -> Result<Vec<HtmlToken>> {
+        let mut html: Vec<HtmlToken> = Vec::new();
+        let mut pairs = for_pair.into_inner();
+        let line = pairs.next().unwrap().line_col().0;
+        let mut item: Option<String> = None;
+        let mut index: Option<String> = None;
+        let mut collection: Option<String> = None;
+        let mut body: Vec<Pair<Rule>> = Vec::new();
+
+        for pair in pairs {
+            match pair.as_rule() {
                 Rule::block_template_attributes => {
-                    for attribute_pair in pair.into_inner() {
-                        if attribute_pair.as_rule() != Rule::attribute {
-                            return Err(anyhow!("Unexpected block_template_attributes rule"));
+                    for (key, value) in Self::lex_html_attributes(lex_state, pair.into_inner())? {
+                        match (key.as_str(), value) {
+                            ("item", Some(value)) => item = Some(value),
+                            ("index", Some(value)) => index = Some(value),
+                            ("in", Some(value)) => collection = Some(value),
+                            _ => {
+                                return Err(anyhow!(format!(
+                                    "The for block does not accept the attribute '{}'",
+                                    key
+                                )))
+                            }
                         }
+                    }
+                }
+                Rule::block_children | Rule::block_template_content | Rule::block_verbatim => {
+                    body.push(pair)
+                }
+                _ => {
+                    return Err(anyhow!(format!(
+                        "The for block does not accept classes: {:?}",
+                        pair
+                    )))
+                }
+            }
+        }
 
-                        let mut iterator = attribute_pair.into_inner();
-                        let attribute_key_pair = iterator.next().unwrap();
-                        let attribute_value_pair = iterator.next().unwrap();
+        let (Some(item), Some(collection)) = (item, collection) else {
+            return Err(anyhow!(format!(
+                "The for block on line {} expects the attributes 'item' and 'in'",
+                line
+            )));
+        };
+        let items = match placeholder::evaluate_value(&collection, &lex_state.scopes)
+            .context(format!("Failed to evaluate the for block on line {}", line))?
+        {
+            Value::List(items) => items,
+            value => {
+                return Err(anyhow!(format!(
+                    "The for block on line {} expects a list, found '{}'",
+                    line, value
+                )))
+            }
+        };
 
-                        if attribute_key_pair.as_rule() != Rule::attribute_key
-                            || attribute_value_pair.as_rule() != Rule::attribute_value
-                        {
-                            return Err(anyhow!("Attribute pairs out of order"));
-                        }
+        for (position, value) in items.into_iter().enumerate() {
+            let mut scope = Scope::new();
 
-                        template_attributes.insert(
-                            attribute_key_pair.as_span().as_str().to_string(),
-                            attribute_value_pair
-                                .as_span()
-                                .as_str()
-                                .replace("\\\"", "&quot;"),
-                        );
+            scope.insert(item.clone(), value);
+
+            if let Some(index) = &index {
+                scope.insert(index.clone(), Value::Number(position as f64));
+            }
+
+            lex_state.scopes.push(scope);
+
+            for pair in body.iter().cloned() {
+                html.extend(Self::lex_html_body(lex_state, pair)?);
+            }
+
+            lex_state.scopes.pop();
+        }
+
+        Ok(html)
+    }
+
+    fn lex_html_block_template(
+        lex_state: &mut LexerState,
+        template_pair: Pair<Rule>,
+    ) -> Result<Vec<HtmlToken>> {
+        let mut pairs = template_pair.clone().into_inner();
+        let template_name = pairs.next().unwrap();
+
+        match template_name.as_str() {
+            "raw" => return Self::lex_html_block_raw(lex_state, template_pair),
+            "code" => return Self::lex_html_block_code(lex_state, template_pair),
+            "for" => return Self::lex_html_block_for(lex_state, template_pair),
+            _ => {}
+        }
+
+        let mut template = Self::find_template(lex_state, template_name.as_str())?;
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::block_template_classes => {
+                    template
+                        .classes
+                        .extend(Self::lex_html_classes(lex_state, pair.into_inner())?);
+                }
+                Rule::block_template_attributes => {
+                    for (key, value) in Self::lex_html_attributes(lex_state, pair.into_inner())? {
+                        // Values are resolved in the scope of the caller, so that a template can
+                        // pass its own variables down explicitly (e.g. `title="{title}"`).
+                        // Attributes without a value act as flags
+                        template
+                            .attributes
+                            .insert(key, Value::String(value.unwrap_or(String::from("true"))));
                     }
                 }
                 Rule::block_children => {
-                    template_children
-                        .extend(Self::lex_html_block_children(lex_state, pair.into_inner())?);
-                }
-                Rule::block_template_content => {
-                    template_children.push(HtmlToken::BlockLine {
-                        content: replace_template_attributes(
-                            pair.as_span().as_str(),
-                            &lex_state.template_attributes,
-                        )?,
-                    });
+                    let (children, slots) =
+                        Self::lex_html_template_children(lex_state, pair.into_inner())?;
+
+                    template.children.extend(children);
+                    template.slots = slots;
+                }
+                Rule::block_template_content | Rule::block_verbatim => {
+                    template
+                        .children
+                        .extend(Self::lex_html_body(lex_state, pair)?);
                 }
                 _ => {
                     return Err(anyhow!(format!(
@@ -591,22 +1939,188 @@ impl DocumentParser {
             }
         }
 
+        Self::lex_html_template(lex_state, template)
+    }
+
+    /// Looks up a template by its name, either among the definitions or the template directories
+    fn find_template(lex_state: &mut LexerState, name: &str) -> Result<Template> {
+        let path = name.replace('-', "/");
+
+        // Templates defined within the document take precedence over template files
+        // Paths within a definition are relative to the document defining it
+        if let Some((content, directory)) = lex_state.definitions.get(name) {
+            return Ok(Template {
+                path,
+                directory: directory.clone(),
+                content: content.clone(),
+                ..Template::default()
+            });
+        }
+
+        let mut tried_paths: Vec<PathBuf> = Vec::new();
+
+        for directory in template_directories(lex_state) {
+            let file_path = directory.join(&path).with_extension("twml");
+
+            if let Ok(content) = fs::read_to_string(&file_path) {
+                // Paths within a template file are relative to the template itself
+                let directory = file_path.parent().unwrap().to_path_buf();
+                lex_state.dependencies.push(file_path);
+
+                return Ok(Template {
+                    path,
+                    directory,
+                    content,
+                    ..Template::default()
+                });
+            }
+
+            tried_paths.push(file_path);
+        }
+
+        Err(anyhow!(format!(
+            "Failed to find the template '{}', tried:\n{}",
+            path,
+            tried_paths
+                .iter()
+                .map(|path| format!("  {}", path.display()))
+                .collect::<Vec<String>>()
+                .join("\n")
+        )))
+    }
+
+    /// Renders a template within its own lexer state and merges the results into the caller
+    fn lex_html_template(lex_state: &mut LexerState, template: Template) -> Result<Vec<HtmlToken>> {
+        let template_path = template.path;
+        let mut template_attributes = template.attributes;
+
+        let template_pairs =
+            DocumentParser::parse(Rule::document, &template.content).context(format!(
+                "Failed to interpret the provided template '{}'",
+                template_path
+            ))?;
+
+        for slot_name in template.slots.keys() {
+            if !template_pairs
+                .clone()
+                .flatten()
+                .any(|pair| pair.as_rule() == Rule::slot_name && pair.as_str() == slot_name)
+            {
+                return Err(anyhow!(format!(
+                    "The template '{}' has no slot named '{}'",
+                    template_path, slot_name
+                )));
+            }
+        }
+
+        let parameters = Self::template_parameters(template_pairs.clone()).context(format!(
+            "Failed to parse the parameters of '{}'",
+            template_path
+        ))?;
+
+        // Templates without any parameter declarations accept arbitrary attributes
+        if !parameters.is_empty() {
+            for name in template_attributes.keys() {
+                if !parameters.iter().any(|parameter| &parameter.name == name) {
+                    return Err(anyhow!(format!(
+                        "The template '{}' has no parameter named '{}'",
+                        template_path, name
+                    )));
+                }
+            }
+        }
+
+        let is_layout = template.blocks.is_some();
+
+        for parameter in parameters {
+            // Layouts are never called with attributes, but see the variables of the extending
+            // document instead
+            if template_attributes.contains_key(&parameter.name)
+                || (is_layout
+                    && lex_state
+                        .scopes
+                        .iter()
+                        .any(|scope| scope.contains_key(&parameter.name)))
+            {
+                continue;
+            }
+
+            if parameter.required {
+                return Err(anyhow!(format!(
+                    "The template '{}' requires the attribute '{}'",
+                    template_path, parameter.name
+                )));
+            }
+
+            if let Some(default) = parameter.default {
+                template_attributes.insert(parameter.name, default);
+            }
+        }
+
+        // The template sees the variables of all enclosing documents, followed by its own
+        // variables and finally the attributes of this invocation
+        let mut scopes = lex_state.scopes.clone();
+        scopes.push(Scope::new());
+        let declaration_scope = scopes.len() - 1;
+        scopes.push(template_attributes);
+
         let mut inner_lex_state = LexerState {
-            declarations: Declarations::default(),
+            declarations: Declarations {
+                markup: lex_state.declarations.markup,
+                markup_classes: lex_state.declarations.markup_classes.clone(),
+                code_theme: lex_state.declarations.code_theme.clone(),
+                ..Declarations::default()
+            },
             page_number: lex_state.page_number,
             toc: Vec::new(),
-            template_children: Some(template_children),
-            template_classes: Some(template_classes),
-            template_attributes,
+            template_children: Some(template.children),
+            template_slots: Some(template.slots),
+            template_classes: Some(template.classes),
+            layout: None,
+            layout_blocks: template.blocks,
+            definitions: lex_state.definitions.clone(),
+            scopes,
+            declaration_scope,
+            raw: false,
+            base_directory: template.directory,
+            root_directory: Some(
+                lex_state
+                    .root_directory
+                    .clone()
+                    .unwrap_or(lex_state.base_directory.clone()),
+            ),
+            dependencies: Vec::new(),
+            template_directories: lex_state.template_directories.clone(),
         };
 
-        html.extend(Self::lex_html_document(
-            &mut inner_lex_state,
-            DocumentParser::parse(Rule::document, &template_content).context(format!(
-                "Failed to interpret the provided template '{}'",
-                template_path
-            ))?,
-        )?);
+        let html = Self::lex_html_document(&mut inner_lex_state, template_pairs)
+            .context(format!("Failed to render the template '{}'", template_path))?;
+
+        // Blocks a layout neither inserts nor passes on to its own layout are unknown to it
+        if let Some(blocks) = &inner_lex_state.layout_blocks {
+            if let Some(name) = blocks
+                .iter()
+                .find(|(_, block)| !block.used)
+                .map(|(name, _)| name)
+            {
+                return Err(anyhow!(format!(
+                    "The layout '{}' has no block named '{}'",
+                    template_path, name
+                )));
+            }
+        }
+
+        // The page size of a document is usually set by the layout it extends
+        if is_layout {
+            lex_state.declarations.page_width_mm = lex_state
+                .declarations
+                .page_width_mm
+                .or(inner_lex_state.declarations.page_width_mm);
+            lex_state.declarations.page_height_mm = lex_state
+                .declarations
+                .page_height_mm
+                .or(inner_lex_state.declarations.page_height_mm);
+        }
 
         lex_state.page_number = inner_lex_state.page_number;
         lex_state
@@ -625,7 +2139,12 @@ impl DocumentParser {
             .declarations
             .include
             .extend(inner_lex_state.declarations.include);
+        lex_state
+            .declarations
+            .links
+            .extend(inner_lex_state.declarations.links);
         lex_state.toc.extend(inner_lex_state.toc);
+        lex_state.dependencies.extend(inner_lex_state.dependencies);
 
         Ok(html)
     }
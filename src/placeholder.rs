@@ -0,0 +1,491 @@
+use crate::parser::escape_html;
+use anyhow::{anyhow, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+use pest::Parser;
+use pest_derive::Parser;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::OnceLock;
+
+#[derive(Parser)]
+#[grammar = "placeholder.pest"]
+struct PlaceholderParser;
+
+/// Part of a text containing placeholders
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Text as written in the source
+    Text(String),
+    /// Value of a placeholder, which is yet to be escaped by the caller
+    Value(String),
+}
+
+/// Replaces the placeholders within content by the variables of the scopes, which are searched
+/// from the innermost (last) scope outward.
+///
+/// Text in braces that is not a placeholder (e.g. code) and placeholders without filters that do
+/// not refer to any defined variable (e.g. `{1}` or `{n + 1}` in prose) are kept as written, while
+/// doubled braces are an escape for literal braces (e.g. `{{name}}` for `{name}`). The result is
+/// meant for raw output, so that the escape filter escapes html characters.
+pub fn interpolate(content: &str, scopes: &[Scope]) -> Result<String> {
+    Ok(split_segments(content, scopes, true)?
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) | Segment::Value(text) => text,
+        })
+        .collect())
+}
+
+/// Splits content into the text written in the source and the values of its placeholders, see
+/// [`interpolate`]. As the caller escapes the result, the escape filter has no effect.
+pub fn split(content: &str, scopes: &[Scope]) -> Result<Vec<Segment>> {
+    split_segments(content, scopes, false)
+}
+
+fn split_segments(content: &str, scopes: &[Scope], raw: bool) -> Result<Vec<Segment>> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut text = String::new();
+    let mut rest = content;
+
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("{{") {
+            match rest.find("}}") {
+                Some(end) => {
+                    text.push_str(&rest[1..=end]);
+                    rest = &rest[end + 2..];
+                }
+                None => {
+                    text.push_str("{{");
+                    rest = &rest[2..];
+                }
+            }
+
+            continue;
+        }
+
+        let Some(end) = find_closing_brace(rest) else {
+            break;
+        };
+
+        match PlaceholderParser::parse(Rule::placeholder, &rest[1..end]) {
+            Ok(mut pairs) => {
+                let value = evaluate(pairs.next().unwrap(), scopes, raw).context(format!(
+                    "Failed to evaluate the placeholder '{}'",
+                    &rest[..=end]
+                ))?;
+
+                match value {
+                    Some(value) => {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                        segments.push(Segment::Value(value));
+                    }
+                    None => text.push_str(&rest[..=end]),
+                }
+
+                rest = &rest[end + 1..];
+            }
+            Err(error) if is_invalid_placeholder(&rest[1..end], scopes) => {
+                return Err(anyhow!(error)
+                    .context(format!("The placeholder '{}' is invalid", &rest[..=end])));
+            }
+            Err(_) => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    text.push_str(rest);
+    segments.push(Segment::Text(text));
+    segments.retain(|segment| segment != &Segment::Text(String::new()));
+
+    Ok(segments)
+}
+
+/// Returns whether content in braces that is not a placeholder was meant to be one, as it starts
+/// with an expression followed by filters or refers to a defined variable. Other braces, such as
+/// those of code or CSS, are kept as written.
+fn is_invalid_placeholder(content: &str, scopes: &[Scope]) -> bool {
+    let Ok(mut pairs) = PlaceholderParser::parse(Rule::leading_expression, content) else {
+        return false;
+    };
+    let expression_pair = pairs.next().unwrap();
+    let filtered = content[expression_pair.as_span().end()..]
+        .trim_start()
+        .starts_with('|');
+
+    filtered
+        || expression_pair
+            .into_inner()
+            .flatten()
+            .any(|pair| pair.as_rule() == Rule::variable && lookup(pair.as_str(), scopes).is_some())
+}
+
+/// Evaluates the expression of a condition, in which undefined variables are considered false
+pub fn evaluate_condition(expression: &str, scopes: &[Scope]) -> Result<bool> {
+    let mut pairs = PlaceholderParser::parse(Rule::standalone_expression, expression)
+        .context(format!("The condition '{}' is invalid", expression))?;
+    let expression_pair = pairs.next().unwrap().into_inner().next().unwrap();
+
+    Ok(evaluate_expression(expression_pair.into_inner(), scopes, false)?.is_truthy())
+}
+
+/// Returns whether content is a list as a whole (e.g. `["a", "b"]` but not `[Draft] Report`)
+pub fn is_list(content: &str) -> bool {
+    PlaceholderParser::parse(Rule::standalone_list, content).is_ok()
+}
+
+/// Evaluates an expression, in which undefined variables are errors
+pub fn evaluate_value(expression: &str, scopes: &[Scope]) -> Result<Value> {
+    let mut pairs = PlaceholderParser::parse(Rule::standalone_expression, expression)
+        .context(format!("The expression '{}' is invalid", expression))?;
+    let expression_pair = pairs.next().unwrap().into_inner().next().unwrap();
+
+    evaluate_expression(expression_pair.into_inner(), scopes, true)
+}
+
+/// Returns the index of the brace closing the placeholder at the start of content
+fn find_closing_brace(content: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, character) in content.char_indices().skip(1) {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return Some(index),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn pratt_parser() -> &'static PrattParser<Rule> {
+    static PRATT_PARSER: OnceLock<PrattParser<Rule>> = OnceLock::new();
+
+    PRATT_PARSER.get_or_init(|| {
+        PrattParser::new()
+            .op(Op::infix(Rule::or, Assoc::Left))
+            .op(Op::infix(Rule::and, Assoc::Left))
+            .op(Op::prefix(Rule::not))
+            .op(Op::infix(Rule::equal, Assoc::Left)
+                | Op::infix(Rule::not_equal, Assoc::Left)
+                | Op::infix(Rule::less_equal, Assoc::Left)
+                | Op::infix(Rule::greater_equal, Assoc::Left)
+                | Op::infix(Rule::less, Assoc::Left)
+                | Op::infix(Rule::greater, Assoc::Left))
+            .op(Op::infix(Rule::concat, Assoc::Left))
+            .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
+            .op(Op::infix(Rule::multiply, Assoc::Left) | Op::infix(Rule::divide, Assoc::Left))
+            .op(Op::prefix(Rule::negate))
+    })
+}
+
+/// Variables by their name
+pub type Scope = HashMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// An undefined variable within a condition
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn as_number(&self) -> Result<f64> {
+        match self {
+            Value::Number(number) => Ok(*number),
+            Value::String(string) => string
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|number| number.is_finite())
+                .ok_or(anyhow!(format!("The value '{}' is not a number", string))),
+            Value::Null => Err(anyhow!("An undefined value is not a number")),
+            Value::List(_) => Err(anyhow!(format!("The list '{}' is not a number", self))),
+            Value::Map(_) => Err(anyhow!(format!("The map '{}' is not a number", self))),
+            Value::Bool(boolean) => {
+                Err(anyhow!(format!("The value '{}' is not a number", boolean)))
+            }
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(boolean) => *boolean,
+            Value::Number(number) => *number != 0.0,
+            Value::String(string) => !string.is_empty() && string != "false",
+            Value::List(items) => !items.is_empty(),
+            Value::Map(entries) => !entries.is_empty(),
+        }
+    }
+
+    /// Compares numerically if both values are numbers and by their text otherwise
+    fn compare(&self, other: &Value) -> Ordering {
+        match (self.as_number(), other.as_number()) {
+            (Ok(left), Ok(right)) => left.total_cmp(&right),
+            _ => self.to_string().cmp(&other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(number) => {
+                // Rounding hides floating point artifacts (e.g. 0.1 + 0.2 = 0.30000000000000004)
+                let rounded = format!("{:.10}", number);
+                let trimmed = rounded.trim_end_matches('0').trim_end_matches('.');
+
+                write!(formatter, "{}", if trimmed == "-0" { "0" } else { trimmed })
+            }
+            Value::String(string) => write!(formatter, "{}", string),
+            Value::Bool(boolean) => write!(formatter, "{}", boolean),
+            Value::Null => Ok(()),
+            Value::List(items) => write!(
+                formatter,
+                "{}",
+                items
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Value::Map(entries) => write!(
+                formatter,
+                "{}",
+                entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Returns the value of a variable, whose name may be followed by the keys of maps and indices of
+/// lists separated by dots (e.g. `invoice.items.0.price`)
+fn lookup(name: &str, scopes: &[Scope]) -> Option<Value> {
+    let mut segments = name.split('.');
+    let variable = segments.next()?;
+    let mut value = scopes.iter().rev().find_map(|scope| scope.get(variable))?;
+
+    for segment in segments {
+        value = match value {
+            Value::Map(entries) => entries.get(segment)?,
+            Value::List(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(value.clone())
+}
+
+fn unescape_string(pair: Pair<Rule>) -> String {
+    let mut string = String::new();
+    let mut characters = pair.into_inner().as_str().chars();
+
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => string.extend(characters.next()),
+            _ => string.push(character),
+        }
+    }
+
+    string
+}
+
+fn evaluate_literal(pair: Pair<Rule>) -> Result<Value> {
+    match pair.as_rule() {
+        Rule::number => Ok(Value::Number(pair.as_str().parse()?)),
+        Rule::string => Ok(Value::String(unescape_string(pair))),
+        Rule::boolean => Ok(Value::Bool(pair.as_str() == "true")),
+        _ => Err(anyhow!(format!(
+            "Unexpected placeholder literal: {:?}",
+            pair
+        ))),
+    }
+}
+
+/// Evaluates an expression, treating undefined variables as errors if strict and as null otherwise
+fn evaluate_expression(pairs: Pairs<Rule>, scopes: &[Scope], strict: bool) -> Result<Value> {
+    pratt_parser()
+        .map_primary(|primary| match primary.as_rule() {
+            Rule::variable => match lookup(primary.as_str(), scopes) {
+                Some(value) => Ok(value),
+                None if strict => Err(anyhow!(format!(
+                    "The variable '{}' is undefined",
+                    primary.as_str()
+                ))),
+                None => Ok(Value::Null),
+            },
+            Rule::expression => evaluate_expression(primary.into_inner(), scopes, strict),
+            Rule::list => Ok(Value::List(
+                primary
+                    .into_inner()
+                    .map(|item| evaluate_expression(item.into_inner(), scopes, strict))
+                    .collect::<Result<Vec<Value>>>()?,
+            )),
+            _ => evaluate_literal(primary),
+        })
+        .map_prefix(|operator, operand| match operator.as_rule() {
+            Rule::not => Ok(Value::Bool(!operand?.is_truthy())),
+            _ => Ok(Value::Number(-operand?.as_number()?)),
+        })
+        .map_infix(|left, operator, right| {
+            let (left, right) = (left?, right?);
+
+            match operator.as_rule() {
+                Rule::or => return Ok(Value::Bool(left.is_truthy() || right.is_truthy())),
+                Rule::and => return Ok(Value::Bool(left.is_truthy() && right.is_truthy())),
+                Rule::equal => return Ok(Value::Bool(left.compare(&right).is_eq())),
+                Rule::not_equal => return Ok(Value::Bool(left.compare(&right).is_ne())),
+                Rule::less_equal => return Ok(Value::Bool(left.compare(&right).is_le())),
+                Rule::greater_equal => return Ok(Value::Bool(left.compare(&right).is_ge())),
+                Rule::less => return Ok(Value::Bool(left.compare(&right).is_lt())),
+                Rule::greater => return Ok(Value::Bool(left.compare(&right).is_gt())),
+                Rule::concat => return Ok(Value::String(format!("{}{}", left, right))),
+                _ => {}
+            }
+
+            let (left, right) = (left.as_number()?, right.as_number()?);
+
+            match operator.as_rule() {
+                Rule::add => Ok(Value::Number(left + right)),
+                Rule::subtract => Ok(Value::Number(left - right)),
+                Rule::multiply => Ok(Value::Number(left * right)),
+                Rule::divide if right == 0.0 => Err(anyhow!("Division by zero")),
+                Rule::divide => Ok(Value::Number(left / right)),
+                _ => Err(anyhow!(format!(
+                    "Unexpected placeholder operator: {:?}",
+                    operator
+                ))),
+            }
+        })
+        .parse(pairs)
+}
+
+/// Returns the value of a placeholder or nothing if it is to be kept as written, as it has no
+/// filters and does not refer to any defined variable
+fn evaluate(placeholder_pair: Pair<Rule>, scopes: &[Scope], raw: bool) -> Result<Option<String>> {
+    let mut pairs = placeholder_pair.into_inner();
+    let expression_pair = pairs.next().unwrap();
+    let filtered = pairs.clone().any(|pair| pair.as_rule() == Rule::filter);
+    let defined =
+        expression_pair.clone().into_inner().flatten().any(|pair| {
+            pair.as_rule() == Rule::variable && lookup(pair.as_str(), scopes).is_some()
+        });
+
+    if !filtered && !defined {
+        return Ok(None);
+    }
+
+    let terms: Vec<Pair<Rule>> = expression_pair.clone().into_inner().collect();
+    let mut value = match terms.as_slice() {
+        [term] if term.as_rule() == Rule::variable => lookup(term.as_str(), scopes),
+        _ => Some(evaluate_expression(
+            expression_pair.clone().into_inner(),
+            scopes,
+            true,
+        )?),
+    };
+
+    for filter_pair in pairs {
+        if filter_pair.as_rule() != Rule::filter {
+            continue;
+        }
+
+        let mut filter_pairs = filter_pair.into_inner();
+        let filter_name = filter_pairs.next().unwrap().as_str();
+        let argument = match filter_pairs.next() {
+            Some(pair) => Some(evaluate_literal(pair.into_inner().next().unwrap())?),
+            None => None,
+        };
+
+        value = Some(apply_filter(
+            filter_name,
+            argument,
+            value,
+            expression_pair.as_str(),
+            raw,
+        )?);
+    }
+
+    Ok(value.map(|value| value.to_string()))
+}
+
+fn apply_filter(
+    filter_name: &str,
+    argument: Option<Value>,
+    value: Option<Value>,
+    name: &str,
+    raw: bool,
+) -> Result<Value> {
+    if filter_name == "default" {
+        let default = argument.context("The default filter expects a value (e.g. default:\"\")")?;
+
+        return Ok(value.unwrap_or(default));
+    }
+
+    let value = value.ok_or(anyhow!(format!("The variable '{}' is undefined", name)))?;
+
+    match (filter_name, argument) {
+        ("upper", None) => Ok(Value::String(value.to_string().to_uppercase())),
+        ("lower", None) => Ok(Value::String(value.to_string().to_lowercase())),
+        ("escape", None) if raw => Ok(Value::String(escape_html(&value.to_string()))),
+        // Values that are not used in raw content are escaped anyway
+        ("escape", None) => Ok(value),
+        ("format", Some(Value::String(format))) => {
+            Ok(Value::String(format_date(&value.to_string(), &format)?))
+        }
+        ("format", _) => Err(anyhow!(
+            "The format filter expects a date format (e.g. format:\"%d %B %Y\")"
+        )),
+        ("fixed", Some(Value::Number(places))) if places.fract() == 0.0 => Ok(Value::String(
+            format!("{:.*}", places as usize, value.as_number()?),
+        )),
+        ("fixed", _) => Err(anyhow!(
+            "The fixed filter expects a number of decimal places (e.g. fixed:2)"
+        )),
+        ("upper" | "lower" | "escape", Some(_)) => Err(anyhow!(format!(
+            "The filter '{}' does not accept an argument",
+            filter_name
+        ))),
+        _ => Err(anyhow!(format!("The filter '{}' is unknown", filter_name))),
+    }
+}
+
+/// Formats an ISO 8601 date (e.g. `2024-03-01` or `2024-03-01T12:00:00`) using strftime syntax
+fn format_date(value: &str, format: &str) -> Result<String> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+
+    if items.contains(&Item::Error) {
+        return Err(anyhow!(format!("The date format '{}' is invalid", format)));
+    }
+
+    let date_time = DateTime::parse_from_rfc3339(value)
+        .map(|date_time| date_time.naive_local())
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap())
+        })
+        .context(format!("The value '{}' is not a valid date", value))?;
+
+    Ok(date_time.format_with_items(items.into_iter()).to_string())
+}
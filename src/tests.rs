@@ -1,16 +1,16 @@
-use crate::parser::{DocumentParser, LexerState, Rule};
+use crate::merge;
+use crate::parser::{self, DocumentParser, LexerState, Rule};
+use crate::placeholder::Value;
 use pest::Parser;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 #[test]
 fn build_documentation() {
-    let cwd = env::current_dir().unwrap();
-    let docs = cwd.join("docs");
+    let docs = env::current_dir().unwrap().join("docs");
 
-    env::set_current_dir(&cwd.join(&docs)).unwrap();
-
-    for entry in fs::read_dir(docs).unwrap() {
+    for entry in fs::read_dir(&docs).unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
 
@@ -21,6 +21,872 @@ fn build_documentation() {
         let document = fs::read_to_string(&path).unwrap();
         let pairs = DocumentParser::parse(Rule::document, &document).unwrap();
         let mut lex_state = LexerState::default();
+        lex_state.base_directory = docs.clone();
         let _ = DocumentParser::generate_html(&mut lex_state, pairs).unwrap();
     }
 }
+
+fn render(document: &str) -> String {
+    let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+    let mut lex_state = LexerState::default();
+    DocumentParser::generate_html(&mut lex_state, pairs).unwrap()
+}
+
+/// Returns the error of a document that fails to render, along with its context
+fn render_error(document: &str) -> String {
+    let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+    let error = DocumentParser::generate_html(&mut LexerState::default(), pairs).unwrap_err();
+
+    format!("{:?}", error)
+}
+
+fn render_in(directory: &Path, document: &str) -> (String, LexerState) {
+    let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+    let mut lex_state = LexerState::default();
+    lex_state.base_directory = directory.to_path_buf();
+    let html = DocumentParser::generate_html(&mut lex_state, pairs).unwrap();
+
+    (html, lex_state)
+}
+
+#[test]
+fn element_names() {
+    let html =
+        render("\\h1 Title\n\\my-widget.p-4 Widget\n\\svg\n    \\svg:rect\n    \\foreignObject\n");
+
+    assert!(html.contains("<h1>Title</h1>"));
+    assert!(html.contains("<my-widget class=\"p-4\">Widget</my-widget>"));
+    assert!(html.contains("<svg:rect"));
+    assert!(html.contains("<foreignObject"));
+}
+
+#[test]
+fn void_and_self_closing_elements() {
+    let html = render(
+        "\\div.x\n\\span\n\\br\n\\img{src=\"a.png\"}\n\\svg\n    \\circle.fill-red-500{r=\"2\"}/\n",
+    );
+
+    assert!(html.contains("<div class=\"x\"></div>"));
+    assert!(html.contains("<span></span>"));
+    assert!(html.contains("<br />"));
+    assert!(html.contains("<img src=\"a.png\" />"));
+    assert!(html.contains("<circle class=\"fill-red-500\" r=\"2\" />"));
+}
+
+#[test]
+fn escaping_and_raw_blocks() {
+    let html = render(
+        "\\p{title=\"a \\\"b\\\" & c\"} a < b & c\n\\!raw <b>bold</b>\n\\!raw\n    \\i <i>x</i>\n\\script\n    if (a < b) {}\n",
+    );
+
+    assert!(html.contains("<p title=\"a &quot;b&quot; &amp; c\">a &lt; b &amp; c</p>"));
+    assert!(html.contains("<b>bold</b>"));
+    assert!(html.contains("<i><i>x</i></i>"));
+    assert!(html.contains("if (a < b) {}"));
+}
+
+#[test]
+fn attribute_names_and_values() {
+    let html = render(
+        "\\input{type=\"checkbox\" checked data-id='3' aria-label='It\\'s \"closed\"'}\n\\svg\n    \\path{stroke-width=\"2\" xlink:href=\"#a\"}/\n",
+    );
+
+    assert!(html.contains(" checked"));
+    assert!(html.contains(" data-id=\"3\""));
+    assert!(html.contains(" aria-label=\"It's &quot;closed&quot;\""));
+    assert!(html.contains(" stroke-width=\"2\""));
+    assert!(html.contains(" xlink:href=\"#a\""));
+}
+
+#[test]
+fn id_shorthand_and_attribute_order() {
+    let html = render("\\div#intro.p-4{data-b=\"2\" data-a=\"1\" title=\"t\"} Intro\n");
+
+    assert!(html.contains(
+        "<div id=\"intro\" class=\"p-4\" data-b=\"2\" data-a=\"1\" title=\"t\">Intro</div>"
+    ));
+}
+
+#[test]
+fn inline_elements() {
+    let html = render(
+        "This is \\span.italic[emphasised \\b[and bold]] text\n\\p.toc A \\a{href=\"#x\"}[link] and \\br[] break\n\\div.w-[10px] \\i.text-[#fff][x]\n",
+    );
+
+    assert!(html.contains("This is <span class=\"italic\">emphasised <b>and bold</b></span> text"));
+    assert!(html.contains("<p class=\"toc\">A <a href=\"#x\">link</a> and <br /> break</p>"));
+    assert!(html.contains("<div class=\"w-[10px]\"><i class=\"text-[#fff]\">x</i></div>"));
+
+    // LaTeX commands followed by a bracket are escaped by doubling their backslash
+    let html = render("\\p Root $\\\\sqrt[3]{x}$ and \\(\\\\left[ x \\right] \\\\ y\\)\n");
+
+    assert!(html.contains("<p>Root $\\sqrt[3]{x}$ and \\(\\left[ x \\right] \\\\ y\\)</p>"));
+}
+
+#[test]
+fn inline_markup() {
+    let plain = render("A *b* _c_\n");
+    let html = render(
+        "@markup on\n@markup-code font-mono bg-slate-100\n\nSome *bold _and italic_*, `a < *b*` and [a link](https://haml.info/) but not 2 * 3 * 4 or snake_case_name\n",
+    );
+
+    assert!(plain.contains("A *b* _c_"));
+    assert!(html.contains(
+        "Some <strong class=\"font-bold\">bold <em class=\"italic\">and italic</em></strong>"
+    ));
+    assert!(html.contains("<code class=\"font-mono bg-slate-100\">a &lt; *b*</code>"));
+    assert!(html.contains("<a class=\"underline\" href=\"https://haml.info/\">a link</a>"));
+    assert!(html.contains("but not 2 * 3 * 4 or snake_case_name"));
+
+    // Values of variables are never interpreted as markup, although they may be emphasised
+    let html = render("@markup on\n@set x a*b*c\n\n{x} and *{x}*\n");
+
+    assert!(html.contains("a*b*c and <strong class=\"font-bold\">a*b*c</strong>"));
+}
+
+#[test]
+fn verbatim_blocks() {
+    let html = render("\\div\n    \\pre.text-sm|\n        \\p {name} <b>\n\n          \\\\ not a comment\n    \\p After\n");
+
+    assert!(
+        html.contains("<pre class=\"text-sm\">\\p {name} &lt;b&gt;\n\n  \\\\ not a comment</pre>")
+    );
+    assert!(html.contains("<p>After</p>"));
+}
+
+#[test]
+fn code_blocks() {
+    let html =
+        render("\\!code.p-2{lang=\"rust\"}|\n    fn main() {\n        let a = 1 < 2;\n    }\n");
+
+    assert!(html.contains("<pre class=\"p-2\" style=\"background-color: #"));
+    assert!(html.contains("<span style=\"color:#"));
+    assert!(html.contains("&lt;"));
+
+    let pairs = DocumentParser::parse(Rule::document, "\\!code{lang=\"nonexistent\"} a\n").unwrap();
+    assert!(DocumentParser::generate_html(&mut LexerState::default(), pairs).is_err());
+}
+
+#[test]
+fn code_from_source_files() {
+    let directory = env::temp_dir().join("twml-test-code");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(
+        directory.join("example.py"),
+        "import os\n\ndef main():\n    # twml:region body\n    print(1)\n    # twml:endregion\n",
+    )
+    .unwrap();
+
+    let (html, lex_state) = render_in(
+        &directory,
+        "\\!code{src=\"example.py\" lines=\"3-5\" numbers}\n",
+    );
+
+    assert!(html.contains(">3 </span>"));
+    assert!(html.contains(">5 </span>"));
+    assert!(!html.contains("import"));
+    assert_eq!(lex_state.dependencies, vec![directory.join("example.py")]);
+
+    let (html, _) = render_in(&directory, "\\!code{src=\"example.py\" region=\"body\"}\n");
+
+    assert!(html.contains("print"));
+    assert!(!html.contains("def"));
+    assert!(!html.contains("twml:region"));
+
+    // Files of unknown languages fall back to plain text, unless the language is given explicitly
+    fs::write(directory.join("example.pest"), "rule = { \"a\" }\n").unwrap();
+
+    let (html, _) = render_in(&directory, "\\!code{src=\"example.pest\"}\n");
+
+    assert!(html.contains("rule = { &quot;a&quot; }"));
+
+    let pairs = DocumentParser::parse(
+        Rule::document,
+        "\\!code{src=\"example.pest\" lang=\"pest\"}\n",
+    )
+    .unwrap();
+    let mut lex_state = LexerState::default();
+    lex_state.base_directory = directory;
+    let error = DocumentParser::generate_html(&mut lex_state, pairs).unwrap_err();
+
+    assert!(format!("{:?}", error).contains("The code language 'pest' is unknown"));
+}
+
+#[test]
+fn document_variables() {
+    let html =
+        render("@set company ACME Corp\n@set version 1.2\n\n\\p {company} {version} {unknown}\n");
+
+    assert!(html.contains("<p>ACME Corp 1.2 {unknown}</p>"));
+
+    // Only values that are lists as a whole are evaluated
+    let html =
+        render("@set title [Draft] Report\n@set tags [\"a\", \"b\"]\n\n\\p {title} {tags.1}\n");
+
+    assert!(html.contains("<p>[Draft] Report b</p>"));
+}
+
+#[test]
+fn nested_template_variables() {
+    let html = render(concat!(
+        "@set company ACME\n",
+        "@define chapter\n",
+        "    @set chapter-kind Chapter\n",
+        "\n",
+        "    \\section\n",
+        "        \\!heading\n",
+        "        \\!heading{title=\"{title} (continued)\"}\n",
+        "@define heading\n",
+        "    \\h2 {chapter-kind}: {title} by {company}\n",
+        "\n",
+        "\\!chapter{title=\"Results\"}\n",
+        "\\p {chapter-kind}\n",
+    ));
+
+    // Nested templates see the attributes and variables of the templates calling them, while
+    // variables of a template are not visible to its caller
+    assert!(html.contains("<h2>Chapter: Results by ACME</h2>"));
+    assert!(html.contains("<h2>Chapter: Results (continued) by ACME</h2>"));
+    assert!(html.contains("<p>{chapter-kind}</p>"));
+}
+
+#[test]
+fn placeholders_in_classes_and_attributes() {
+    let html = render(concat!(
+        "@set color red\n@set url https://example.org\n\n",
+        "\\div.bg-{color}-100.{color}{hidden}\n",
+        "\\a.text-[{color}]{href=\"{url}/about\" title=\"{color} & {color}\"} About\n",
+    ));
+
+    assert!(html.contains("<div class=\"bg-red-100 red\" hidden></div>"));
+    assert!(html.contains(
+        "<a class=\"text-[red]\" href=\"https://example.org/about\" title=\"red &amp; red\">About</a>"
+    ));
+    assert!(html.contains(".bg-red-100"));
+
+    let html = render(concat!(
+        "@set n 2\n@set color red\n\n",
+        "\\div#sec-{n}.text{color}-500 Section\n",
+        "\\p#{color}_{n} Red\n",
+        "\\input#field{disabled}\n",
+    ));
+
+    assert!(html.contains("<div id=\"sec-2\" class=\"textred-500\">Section</div>"));
+    assert!(html.contains("<p id=\"red_2\">Red</p>"));
+    assert!(html.contains("<input id=\"field\" disabled />"));
+
+    // Values within classes cannot close the attribute
+    let html = render("@set c x\" onclick=\"<b>\n\n\\p.bg-{c} A \\span.text-{c}[B]\n");
+
+    assert!(html.contains(
+        "<p class=\"bg-x&quot; onclick=&quot;&lt;b&gt;\">A <span class=\"text-x&quot; onclick=&quot;&lt;b&gt;\">B</span></p>"
+    ));
+}
+
+#[test]
+fn placeholder_filters() {
+    let html = render(concat!(
+        "@set name Ada\n@set date 2024-03-01\n@set markup <b>\n\n",
+        "\\p {name|upper} {name | lower} {title|default:\"Untitled\"} {date|format:\"%d %B %Y\"}\n",
+        "\\script {markup|escape} {{name}} {unknown}\n",
+        "\\p {markup|escape} {markup}\n",
+    ));
+
+    assert!(html.contains("<p>ADA ada Untitled 01 March 2024</p>"));
+    assert!(html.contains("<script>&lt;b&gt; {name} {unknown}</script>"));
+    assert!(html.contains("<p>&lt;b&gt; &lt;b&gt;</p>"));
+
+    for (document, message) in [
+        (
+            "@set name Ada\n\n\\p {name|reverse}\n",
+            "The filter 'reverse' is unknown",
+        ),
+        ("\\p {name|upper}\n", "The variable 'name' is undefined"),
+        (
+            "@set date tomorrow\n\n\\p {date|format:\"%Y\"}\n",
+            "The value 'tomorrow' is not a valid date",
+        ),
+        (
+            "@set title Report\n\n\\p {title|default:Untitled}\n",
+            "The placeholder '{title|default:Untitled}' is invalid",
+        ),
+        (
+            "@set title Report\n\n\\p {title|Upper}\n",
+            "The placeholder '{title|Upper}' is invalid",
+        ),
+    ] {
+        assert!(render_error(document).contains(message));
+    }
+}
+
+#[test]
+fn placeholder_expressions() {
+    let html = render(concat!(
+        "@set qty 3\n@set price 2.5\n@set tenth 0.1\n@set first Ada\n@set last Lovelace\n\n",
+        "\\p {qty * price} {qty * price + 1|fixed:2} {tenth + 0.2} {-(qty - 5) / 4}\n",
+        "\\p {first ~ \" \" ~ last} {\"Total: \" ~ qty * price|upper}\n",
+    ));
+
+    assert!(html.contains("<p>7.5 8.50 0.3 0.5</p>"));
+    assert!(html.contains("<p>Ada Lovelace TOTAL: 7.5</p>"));
+
+    // Braces without filters or defined variables are kept as written, and doubled braces are
+    // written as single braces
+    let html = render(concat!(
+        "@set qty 3\n\n",
+        "\\p Use {1} or {\"x\"} for the set {n + 1}\n",
+        "\\p {{qty}} is {qty}, {{qty * 2}} is {qty * 2}\n",
+        "\\style a{b{c:d}}\n",
+    ));
+
+    assert!(html.contains("<p>Use {1} or {\"x\"} for the set {n + 1}</p>"));
+    assert!(html.contains("<p>{qty} is 3, {qty * 2} is 6</p>"));
+    assert!(html.contains("<style>a{b{c:d}}</style>"));
+
+    let document = "@set qty three\n\n\\p Items\n\\p {qty * 2}\n";
+    let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+    let error = DocumentParser::generate_html(&mut LexerState::default(), pairs).unwrap_err();
+
+    assert!(format!("{:?}", error).contains("line 4"));
+    assert!(format!("{:?}", error).contains("'three' is not a number"));
+}
+
+#[test]
+fn conditional_blocks() {
+    let html = render(concat!(
+        "@set title Report\n@set draft false\n@set pages 12\n\n",
+        "\\!if{cond=\"title\"}\n    \\h1 {title}\n\\!else\n    \\h1 Untitled\n",
+        "\\!if{cond=\"subtitle or draft\"} Hidden\n\\!else Shown\n",
+        "\\!if{cond='pages > 20'} Long\n\n\\!else{cond='pages >= 10 and not draft'} Medium\n\\!else Short\n",
+        "\\!if{cond='title != \"Report\"'}\n    \\p {undefined|upper}\n\\!else\n    \\p Else\n",
+    ));
+
+    assert!(html.contains("<h1>Report</h1>"));
+    assert!(!html.contains("Untitled"));
+    assert!(html.contains("Shown") && !html.contains("Hidden"));
+    assert!(html.contains("Medium") && !html.contains("Long") && !html.contains("Short"));
+    assert!(html.contains("<p>Else</p>"));
+
+    for (document, message) in [
+        (
+            "\\!else\n    \\p Else\n",
+            "An else block must directly follow an if block",
+        ),
+        (
+            "\\!if\n    \\p If\n",
+            "The if block on line 1 expects a condition",
+        ),
+    ] {
+        assert!(render_error(document).contains(message));
+    }
+}
+
+#[test]
+fn for_blocks() {
+    let html = render(concat!(
+        "@set colors [\"red\", \"green\"]\n@set prices [2, 3.5]\n\n",
+        "\\ul\n    \\!for{item=\"color\" index=\"i\" in=\"colors\"}\n        \\li.text-{color}-500 {i + 1}. {color}\n",
+        "\\!for{item=\"price\" in=\"prices\"} {price * 2|fixed:2}\n",
+        "\\!for{item=\"x\" in=\"[]\"} Nothing\n",
+    ));
+
+    assert!(html.contains("<li class=\"text-red-500\">1. red</li>"));
+    assert!(html.contains("<li class=\"text-green-500\">2. green</li>"));
+    assert!(html.contains("4.00") && html.contains("7.00"));
+    assert!(!html.contains("Nothing"));
+
+    for (document, message) in [
+        (
+            "\\!for{item=\"x\" in=\"missing\"} {x}\n",
+            "The variable 'missing' is undefined",
+        ),
+        (
+            "@set name Ada\n\n\\!for{item=\"x\" in=\"name\"} {x}\n",
+            "The for block on line 3 expects a list, found 'Ada'",
+        ),
+        (
+            "\\!for{in=\"[1]\"} Item\n",
+            "The for block on line 1 expects the attributes 'item' and 'in'",
+        ),
+    ] {
+        assert!(render_error(document).contains(message));
+    }
+}
+
+#[test]
+fn data_files() {
+    let directory = env::temp_dir().join("twml-test-data");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(
+        directory.join("invoice.json"),
+        r#"{"customer": {"name": "Ada", "address": "London"}, "items": [{"price": 2.5}, {"price": 4}]}"#,
+    )
+    .unwrap();
+    fs::write(
+        directory.join("company.toml"),
+        "name = \"ACME\"\nfounded = 1999\n",
+    )
+    .unwrap();
+    fs::write(
+        directory.join("staff.csv"),
+        "name,role\nGrace,Admiral\nAlan,Scientist\n",
+    )
+    .unwrap();
+
+    let (html, lex_state) = render_in(
+        &directory,
+        concat!(
+            "@data invoice invoice.json\n@data company company.toml\n@data staff staff.csv\n\n",
+            "\\p {invoice.customer.name}, {invoice.customer.address} {invoice.items.1.price}\n",
+            "\\p {company.name} {company.founded + 25}\n",
+            "\\!for{item=\"person\" in=\"staff\"}\n    \\p {person.name} ({person.role})\n",
+        ),
+    );
+
+    assert!(html.contains("<p>Ada, London 4</p>"));
+    assert!(html.contains("<p>ACME 2024</p>"));
+    assert!(html.contains("<p>Grace (Admiral)</p>"));
+    assert!(html.contains("<p>Alan (Scientist)</p>"));
+    assert_eq!(
+        lex_state.dependencies,
+        vec![
+            directory.join("invoice.json"),
+            directory.join("company.toml"),
+            directory.join("staff.csv")
+        ]
+    );
+}
+
+#[test]
+fn mail_merge() {
+    let directory = env::temp_dir().join("twml-test-merge");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(
+        directory.join("records.csv"),
+        "name,number\nAda,1\n../Grace,2\na/b,3\n",
+    )
+    .unwrap();
+    fs::write(directory.join("list.json"), r#"[{"name": "Ada"}, "Grace"]"#).unwrap();
+
+    let records = merge::read_records(&directory.join("records.csv")).unwrap();
+
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+        merge::output_paths("out/{name}-{number + 1}.pdf", &records).unwrap(),
+        vec!["out/Ada-2.pdf", "out/__Grace-3.pdf", "out/a_b-4.pdf"]
+    );
+    assert!(format!(
+        "{:?}",
+        merge::output_paths("out/{missing}.pdf", &records).unwrap_err()
+    )
+    .contains("shared by multiple records"));
+    assert!(format!(
+        "{:?}",
+        merge::read_records(&directory.join("list.json")).unwrap_err()
+    )
+    .contains("Every record is expected to map names to values"));
+
+    // The fields of a record take precedence over the defaults set by the document
+    let pairs =
+        DocumentParser::parse(Rule::document, "@set name Preview\n\n\\p Dear {name}\n").unwrap();
+    let mut lex_state = LexerState::default();
+    lex_state.push_scope(records[0].clone());
+    let html = DocumentParser::generate_html(&mut lex_state, pairs).unwrap();
+
+    assert!(html.contains("<p>Dear Ada</p>"));
+}
+
+#[test]
+fn inline_definitions() {
+    let html = render(concat!(
+        "@set company ACME\n",
+        "@define card\n",
+        "    @define card-title\n",
+        "        \\h2.$ {title} ({company}, {kind|default:\"plain\"})\n",
+        "\n",
+        "    \\div.border.$\n",
+        "        \\!card-title.text-{color}-500{title=\"{heading}\"}\n",
+        "        \\section\n",
+        "            {...}\n",
+        "\n",
+        "\\!card.p-2{heading=\"Welcome\" color=\"red\"}\n",
+        "    \\p Body\n",
+    ));
+
+    assert!(html.contains("<div class=\"border p-2\">"));
+    assert!(html.contains("<h2 class=\"text-red-500\">Welcome (ACME, plain)</h2>"));
+    assert!(html.contains("<p>Body</p>"));
+
+    let pairs = DocumentParser::parse(Rule::document, "@define code\n    \\p Code\n").unwrap();
+
+    assert!(DocumentParser::generate_html(&mut LexerState::default(), pairs).is_err());
+}
+
+#[test]
+fn inline_definition_paths() {
+    let directory = env::temp_dir().join("twml-test-definition-paths");
+    fs::create_dir_all(directory.join("parts")).unwrap();
+    fs::write(directory.join("seal.twml"), "\\p Root seal\n").unwrap();
+    fs::write(directory.join("parts/seal.twml"), "\\p Parts seal\n").unwrap();
+    fs::write(directory.join("parts/card.twml"), "\\div\n    \\!badge\n").unwrap();
+
+    // Paths within a definition are relative to the defining document, not to its caller
+    let (html, lex_state) = render_in(
+        &directory,
+        "@define badge\n    @css badge.css\n\n    \\!seal\n\n\\!parts-card\n",
+    );
+
+    assert!(html.contains("<p>Root seal</p>"));
+    assert_eq!(
+        lex_state.declarations.links,
+        vec![(directory.join("badge.css"), String::from("badge.css"))]
+    );
+}
+
+#[test]
+fn template_directories() {
+    let directory = env::temp_dir().join("twml-test-templates");
+    fs::create_dir_all(directory.join("cards")).unwrap();
+    fs::write(directory.join("cards/info.twml"), "\\div.$ {title}\n").unwrap();
+
+    let document = "\\!cards-info.p-2{title=\"Info\"}\n";
+    let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+    let mut lex_state = LexerState::default();
+    lex_state.template_directories = vec![directory.clone()];
+    let html = DocumentParser::generate_html(&mut lex_state, pairs).unwrap();
+
+    assert!(html.contains("<div class=\"p-2\">Info</div>"));
+
+    let pairs = DocumentParser::parse(Rule::document, "\\!cards-missing\n").unwrap();
+    let mut lex_state = LexerState::default();
+    lex_state.template_directories = vec![directory.clone()];
+    let error = DocumentParser::generate_html(&mut lex_state, pairs).unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains(&directory.join("cards/missing.twml").display().to_string()));
+    assert!(error
+        .to_string()
+        .contains("/usr/share/twml/templates/cards/missing.twml"));
+}
+
+#[test]
+fn environment_template_directories() {
+    let directories = parser::environment_template_directories(
+        Some(OsString::from(
+            "~/templates::/opt/templates:~other/templates",
+        )),
+        Some(OsString::from("/etc/xdg")),
+        Some(OsString::from("/home/ada")),
+    );
+
+    assert_eq!(
+        directories,
+        vec![
+            PathBuf::from("/home/ada/templates"),
+            PathBuf::from("/opt/templates"),
+            PathBuf::from("~other/templates"),
+            PathBuf::from("/etc/xdg/twml/templates"),
+            PathBuf::from("/usr/share/twml/templates"),
+        ]
+    );
+
+    // Without XDG_CONFIG_HOME, user templates are looked up within the home directory
+    for config_home in [None, Some(OsString::new())] {
+        assert_eq!(
+            parser::environment_template_directories(
+                None,
+                config_home,
+                Some(OsString::from("/home/ada"))
+            ),
+            vec![
+                PathBuf::from("/home/ada/.config/twml/templates"),
+                PathBuf::from("/usr/share/twml/templates"),
+            ]
+        );
+    }
+
+    assert_eq!(
+        parser::environment_template_directories(Some(OsString::from("~/templates")), None, None),
+        vec![
+            PathBuf::from("~/templates"),
+            PathBuf::from("/usr/share/twml/templates"),
+        ]
+    );
+}
+
+#[test]
+fn relative_paths() {
+    let directory = env::temp_dir().join("twml-test-relative");
+    fs::create_dir_all(directory.join("parts")).unwrap();
+    fs::create_dir_all(directory.join("images")).unwrap();
+    fs::write(directory.join("footer.twml"), "\\footer Footer\n").unwrap();
+    fs::write(
+        directory.join("parts/letter.twml"),
+        "@css letter.css\n@js https://example.org/script.js\n\n\\div\n    \\!sender\n    \\!footer\n",
+    )
+    .unwrap();
+    fs::write(directory.join("parts/sender.twml"), "\\p Sender\n").unwrap();
+    fs::write(
+        directory.join("parts/letter.css"),
+        "p { background: url(\"../images/paper.png\"); }\n",
+    )
+    .unwrap();
+    fs::write(directory.join("images/paper.png"), "").unwrap();
+    fs::write(directory.join("letter.css"), "body {}\n").unwrap();
+
+    let (html, lex_state) = render_in(&directory, "@css letter.css\n\n\\!parts-letter\n");
+
+    // Linked files are referenced relative to the root document, as written by twml-html
+    assert!(html.contains("<p>Sender</p>"));
+    assert!(html.contains("<footer>Footer</footer>"));
+    assert!(html.contains("<link rel=\"stylesheet\" href=\"letter.css\" />"));
+    assert!(html.contains("<link rel=\"stylesheet\" href=\"parts/letter.css\" />"));
+    assert!(html.contains("<script src=\"https://example.org/script.js\"></script>"));
+    assert_eq!(
+        lex_state.declarations.links,
+        vec![
+            (directory.join("letter.css"), String::from("letter.css")),
+            (
+                directory.join("parts/letter.css"),
+                String::from("parts/letter.css")
+            )
+        ]
+    );
+
+    // Copies keep the layout, so that files of the same name and urls within stylesheets remain
+    let output = env::temp_dir().join("twml-test-relative-output");
+    fs::create_dir_all(&output).unwrap();
+    DocumentParser::include_linked_files(&lex_state.declarations, &output).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(output.join("letter.css")).unwrap(),
+        "body {}\n"
+    );
+    assert!(output.join("parts/letter.css").is_file());
+    assert!(output.join("images/paper.png").is_file());
+
+    let (html, _) = render_in(
+        &directory.join("parts"),
+        "@css ../letter.css\n@css ./sub/../letter.css\n",
+    );
+
+    assert!(html.contains(&format!(
+        "<link rel=\"stylesheet\" href=\"{}\" />",
+        directory.join("letter.css").display()
+    )));
+    assert!(html.contains("<link rel=\"stylesheet\" href=\"letter.css\" />"));
+}
+
+#[test]
+fn named_slots() {
+    let definitions = concat!(
+        "@define letter\n",
+        "    \\header\n",
+        "        \\address\n",
+        "            {...sender}\n",
+        "        \\address\n",
+        "            {...recipient}\n",
+        "    \\aside\n",
+        "        {...note?}\n",
+        "    \\main\n",
+        "        {...}\n",
+        "\n",
+    );
+    let html = render(&format!(
+        "{}{}",
+        definitions,
+        concat!(
+            "\\!letter\n",
+            "    \\slot:sender ACME Corp\n",
+            "    \\p Dear Ada,\n",
+            "    \\slot:recipient\n",
+            "        \\p Ada Lovelace\n",
+            "        \\p London\n",
+        )
+    ));
+
+    let compact: String = html.split_whitespace().collect();
+
+    assert!(compact.contains("<address>ACMECorp</address>"));
+    assert!(compact.contains("<address><p>AdaLovelace</p><p>London</p></address>"));
+    assert!(compact.contains("<aside></aside>"));
+    assert!(compact.contains("<main><p>DearAda,</p></main>"));
+    assert!(!html.contains("slot"));
+
+    for (call, message) in [
+        (
+            "\\!letter\n    \\slot:sender ACME\n",
+            "The slot 'recipient' has not been provided (e.g. by \\slot:recipient)",
+        ),
+        (
+            "\\!letter\n    \\slot:sender A\n    \\slot:recipient B\n    \\slot:footer C\n",
+            "The template 'letter' has no slot named 'footer'",
+        ),
+        (
+            "\\!letter\n    \\slot:sender A\n    \\!if{cond=\"true\"}\n        \\slot:recipient B\n",
+            "The slot on line 15 is not a direct child of a template call",
+        ),
+        (
+            "\\!letter\n    \\slot:sender A\n    \\slot:recipient B\n    \\div\n        \\slot:note C\n",
+            "The slot on line 16 is not a direct child of a template call",
+        ),
+    ] {
+        assert!(render_error(&format!("{}{}", definitions, call)).contains(message));
+    }
+}
+
+#[test]
+fn template_parameters() {
+    let definitions = concat!(
+        "@define badge\n",
+        "    @param label required\n",
+        "    @param color default=\"slate\"\n",
+        "    @param icon\n",
+        "\n",
+        "    \\span.bg-{color}-100 {label}{icon|default:\"\"}\n",
+        "\n",
+    );
+    let html = render(&format!(
+        "{}\\!badge{{label=\"New\"}}\n\\!badge{{label=\"Old\" color=\"red\"}}\n",
+        definitions
+    ));
+
+    assert!(html.contains("<span class=\"bg-slate-100\">New</span>"));
+    assert!(html.contains("<span class=\"bg-red-100\">Old</span>"));
+
+    for (call, message) in [
+        (
+            "\\!badge\n",
+            "The template 'badge' requires the attribute 'label'",
+        ),
+        (
+            "\\!badge{label=\"New\" size=\"2\"}\n",
+            "The template 'badge' has no parameter named 'size'",
+        ),
+    ] {
+        assert!(render_error(&format!("{}{}", definitions, call)).contains(message));
+    }
+
+    // Layouts receive their parameters from the variables of the extending document
+    let layout = concat!(
+        "@define page\n",
+        "    @param title required\n",
+        "    @param subtitle default=\"Draft\"\n",
+        "\n",
+        "    \\h1 {title}\n",
+        "    \\h2 {subtitle}\n",
+        "\n",
+    );
+    let html = render(&format!(
+        "@set title Report\n@set subtitle Final\n@extends page\n{}",
+        layout
+    ));
+
+    assert!(html.contains("<h1>Report</h1>"));
+    assert!(html.contains("<h2>Final</h2>"));
+    assert!(render_error(&format!("@extends page\n{}", layout))
+        .contains("The template 'page' requires the attribute 'title'"));
+
+    let pairs = DocumentParser::parse(Rule::document, "@param color default=\"slate\"\n").unwrap();
+    let parameters = DocumentParser::template_parameters(pairs).unwrap();
+
+    assert_eq!(parameters[0].name, "color");
+    assert!(!parameters[0].required);
+    assert_eq!(
+        parameters[0].default,
+        Some(Value::String(String::from("slate")))
+    );
+}
+
+#[test]
+fn layouts() {
+    let directory = env::temp_dir().join("twml-test-layouts");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(
+        directory.join("base.twml"),
+        concat!(
+            "@page-width 148\n",
+            "\n",
+            "\\header\n",
+            "    \\block:title Untitled\n",
+            "\\main\n",
+            "    \\block:content\n",
+            "        \\p Empty\n",
+            "\\footer\n",
+            "    \\block:footer {company}\n",
+        ),
+    )
+    .unwrap();
+    fs::write(
+        directory.join("report.twml"),
+        "@extends base\n\n\\block:title Report\n\\block:footer Confidential\n",
+    )
+    .unwrap();
+
+    let (html, lex_state) = render_in(
+        &directory,
+        "@set company ACME\n@extends report\n\n\\block:content\n    \\p Results\n\n\\block:title Q3\n",
+    );
+    let compact: String = html.split_whitespace().collect();
+
+    assert!(compact.contains("<header>Q3</header>"));
+    assert!(compact.contains("<main><p>Results</p></main>"));
+    assert!(compact.contains("<footer>Confidential</footer>"));
+    assert_eq!(lex_state.declarations.page_width_mm, Some(148));
+
+    let (html, _) = render_in(&directory, "@set company ACME\n@extends base\n");
+
+    let compact: String = html.split_whitespace().collect();
+
+    assert!(compact.contains("<header>Untitled</header>"));
+    assert!(compact.contains("<main><p>Empty</p></main>"));
+    assert!(compact.contains("<footer>ACME</footer>"));
+
+    // Pages of the overridden blocks are counted where the layout inserts them
+    fs::write(
+        directory.join("book.twml"),
+        "\\div.page.toc Cover\n\\block:content\n\\div.page.toc Appendix\n",
+    )
+    .unwrap();
+
+    let (_, lex_state) = render_in(
+        &directory,
+        "@extends book\n\n\\block:content\n    \\div.page\n    \\div.toc Chapter\n",
+    );
+
+    assert_eq!(
+        lex_state.toc,
+        vec![
+            (String::from("Cover"), 1),
+            (String::from("Chapter"), 2),
+            (String::from("Appendix"), 3)
+        ]
+    );
+
+    for (document, message) in [
+        (
+            "@extends base\n\n\\p Outside of a block\n",
+            "Only blocks may follow the declarations of a layout's child, found line 3",
+        ),
+        (
+            "@extends base\n\n\\block:title A\n\\block:title B\n",
+            "The block 'title' is overridden more than once",
+        ),
+        (
+            "@extends report\n\n\\block:titel Q3\n",
+            "The layout 'base' has no block named 'titel'",
+        ),
+        (
+            "@extends missing\n",
+            "Failed to find the template 'missing'",
+        ),
+    ] {
+        let pairs = DocumentParser::parse(Rule::document, document).unwrap();
+        let mut lex_state = LexerState::default();
+        lex_state.base_directory = directory.clone();
+        let error = DocumentParser::generate_html(&mut lex_state, pairs).unwrap_err();
+
+        assert!(format!("{:?}", error).contains(message));
+    }
+}